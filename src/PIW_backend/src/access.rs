@@ -0,0 +1,70 @@
+//! Role registry and the guard functions used in `#[update(guard = ...)]`.
+//! The owner holds every role implicitly and admins hold every role but
+//! Owner; everyone else only has what was granted to them.
+
+use crate::state;
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::caller;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Owner,
+    Admin,
+    Arbiter,
+    Support,
+    Auditor,
+}
+
+const ALL_ROLES: [Role; 5] = [Role::Owner, Role::Admin, Role::Arbiter, Role::Support, Role::Auditor];
+
+pub fn has_role(principal: Principal, role: Role) -> bool {
+    state::read(|s| {
+        if s.owner == Some(principal) {
+            return true;
+        }
+        let Some(granted) = s.roles.get(&principal) else {
+            return false;
+        };
+        granted.contains(&role) || (role != Role::Owner && granted.contains(&Role::Admin))
+    })
+}
+
+pub fn roles_of(principal: Principal) -> Vec<Role> {
+    ALL_ROLES.into_iter().filter(|r| has_role(principal, *r)).collect()
+}
+
+/// Support, auditors and arbiters may look at any contract, not just their own.
+pub fn can_view_all(principal: Principal) -> bool {
+    [Role::Arbiter, Role::Support, Role::Auditor]
+        .into_iter()
+        .any(|r| has_role(principal, r))
+}
+
+fn require(role: Role) -> Result<(), String> {
+    if has_role(caller(), role) {
+        Ok(())
+    } else {
+        Err(format!("caller does not have the {:?} role", role))
+    }
+}
+
+pub fn caller_is_owner() -> Result<(), String> {
+    require(Role::Owner)
+}
+
+pub fn caller_is_admin() -> Result<(), String> {
+    require(Role::Admin)
+}
+
+pub fn caller_is_arbiter() -> Result<(), String> {
+    require(Role::Arbiter)
+}
+
+pub fn caller_is_auditor() -> Result<(), String> {
+    require(Role::Auditor)
+}
+
+/// Logs may mention any contract, so only support and auditors read them.
+pub fn caller_can_read_logs() -> Result<(), String> {
+    require(Role::Support).or_else(|_| require(Role::Auditor))
+}
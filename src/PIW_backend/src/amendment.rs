@@ -0,0 +1,154 @@
+//! Amendments to the terms of an active escrow. One party proposes a change
+//! and the other approves it, at which point the change takes effect and the
+//! terms get a new version. Every version is kept with its SHA-256, so a
+//! dispute can point at the exact terms in force at any time.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::{self, EscrowContract, Milestone};
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AmendmentChange {
+    /// Moves the expiry of the hash lock, which is the contract's delivery
+    /// deadline.
+    SetDeadline { expires_at: u64 },
+    AddMilestone(Milestone),
+    /// Raises the amount by this much, paid in by the payer on approval.
+    TopUp { amount: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Amendment {
+    pub proposed_by: Principal,
+    pub change: AmendmentChange,
+    pub proposed_at: u64,
+}
+
+/// What both parties have agreed to, as opposed to the contract's state.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Terms {
+    pub payer: Principal,
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+    pub deadline: Option<u64>,
+    pub milestones: Vec<Milestone>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TermsVersion {
+    pub version: u32,
+    pub terms: Terms,
+    /// SHA-256 of the Candid encoding of `terms`.
+    pub hash: ByteBuf,
+    pub in_force_from: u64,
+}
+
+impl Terms {
+    pub fn of(contract: &EscrowContract) -> Self {
+        Terms {
+            payer: contract.payer,
+            payee: contract.payee,
+            ledger: contract.ledger,
+            amount: contract.amount,
+            conditions: contract.conditions.clone(),
+            deadline: contract.hash_lock.as_ref().map(|l| l.expires_at),
+            milestones: contract.milestones.clone(),
+        }
+    }
+
+    pub fn hash(&self) -> EscrowResult<ByteBuf> {
+        let encoded = candid::encode_one(self).map_err(|e| EscrowError::Encoding(e.to_string()))?;
+        Ok(ByteBuf::from(Sha256::digest(encoded).to_vec()))
+    }
+}
+
+impl TermsVersion {
+    pub fn new(version: u32, contract: &EscrowContract, in_force_from: u64) -> EscrowResult<Self> {
+        Self::of_terms(version, Terms::of(contract), in_force_from)
+    }
+
+    fn of_terms(version: u32, terms: Terms, in_force_from: u64) -> EscrowResult<Self> {
+        Ok(TermsVersion {
+            version,
+            hash: terms.hash()?,
+            terms,
+            in_force_from,
+        })
+    }
+}
+
+pub fn validate(contract: &EscrowContract, change: &AmendmentChange, now: u64) -> EscrowResult<()> {
+    match change {
+        AmendmentChange::SetDeadline { expires_at } => {
+            if contract.hash_lock.is_none() {
+                return Err(EscrowError::InvalidArgument("contract has no deadline to change".to_string()));
+            }
+            if *expires_at <= now {
+                return Err(EscrowError::InvalidArgument("deadline must be in the future".to_string()));
+            }
+        }
+        AmendmentChange::AddMilestone(milestone) => {
+            escrow::validate_milestones(std::slice::from_ref(milestone), contract.milestones.len(), now)?;
+        }
+        AmendmentChange::TopUp { amount } => {
+            // Split shares are fixed amounts allocated at creation.
+            if contract.splits.is_some() {
+                return Err(EscrowError::InvalidArgument("split escrows cannot be topped up".to_string()));
+            }
+            if *amount == 0 || contract.amount.checked_add(*amount).is_none() {
+                return Err(EscrowError::InvalidArgument("invalid top-up amount".to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes an approved change and records the new terms. Returns the version.
+/// Both hashes are taken before the contract is touched, so an error leaves
+/// it as it was.
+pub fn apply(contract: &mut EscrowContract, change: AmendmentChange, now: u64) -> EscrowResult<TermsVersion> {
+    // Contracts opened before terms were versioned get their original terms
+    // recorded first.
+    let original = if contract.terms_versions.is_empty() {
+        Some(TermsVersion::new(1, contract, contract.created_at)?)
+    } else {
+        None
+    };
+    let mut terms = Terms::of(contract);
+    match change {
+        AmendmentChange::SetDeadline { expires_at } => terms.deadline = terms.deadline.map(|_| expires_at),
+        AmendmentChange::AddMilestone(milestone) => terms.milestones.push(milestone),
+        AmendmentChange::TopUp { amount } => terms.amount += amount,
+    }
+    let number = contract.terms_versions.len().max(1) as u32 + 1;
+    let version = TermsVersion::of_terms(number, terms, now)?;
+
+    contract.terms_versions.extend(original);
+    if let (Some(lock), Some(deadline)) = (contract.hash_lock.as_mut(), version.terms.deadline) {
+        lock.expires_at = deadline;
+    }
+    contract.amount = version.terms.amount;
+    contract.milestones = version.terms.milestones.clone();
+    contract.terms_versions.push(version.clone());
+    Ok(version)
+}
+
+/// The terms that were in force at `timestamp`.
+pub fn in_force_at(contract: &EscrowContract, timestamp: u64) -> EscrowResult<Option<TermsVersion>> {
+    if contract.terms_versions.is_empty() {
+        if timestamp < contract.created_at {
+            return Ok(None);
+        }
+        return TermsVersion::new(1, contract, contract.created_at).map(Some);
+    }
+    Ok(contract
+        .terms_versions
+        .iter()
+        .rev()
+        .find(|v| v.in_force_from <= timestamp)
+        .cloned())
+}
@@ -0,0 +1,71 @@
+//! Mutual cancellation of a funded escrow. Either party proposes to call the
+//! deal off, optionally with part of the escrow going to the payee for work
+//! already done, and the other party confirms. The rest goes back to the
+//! payer. A confirmed cancellation binds both parties: the contract can then
+//! only be paid out as agreed.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::EscrowContract;
+use candid::{CandidType, Deserialize, Principal};
+
+const MAX_REASON_CHARS: usize = 500;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Cancellation {
+    pub proposed_by: Principal,
+    /// What the payee keeps, before the platform fee, which is taken from it
+    /// as from a release.
+    pub payee_amount: u64,
+    pub reason: String,
+    pub proposed_at: u64,
+    pub confirmed_at: Option<u64>,
+    /// Block of the payee's share once it has been paid.
+    pub payee_block: Option<u64>,
+}
+
+impl Cancellation {
+    pub fn is_agreed(&self) -> bool {
+        self.confirmed_at.is_some()
+    }
+
+    /// What has already left the escrow under this cancellation.
+    pub fn paid_out(&self) -> u64 {
+        if self.payee_block.is_some() {
+            self.payee_amount
+        } else {
+            0
+        }
+    }
+}
+
+/// Split escrows have several payees, so they can only be cancelled in full.
+/// Both the payee's share, after the platform fee, and the rest going back
+/// to the payer have to be worth a ledger transfer of `fee`; otherwise the
+/// payout could never go through and the agreed contract would be stuck.
+pub fn validate(contract: &EscrowContract, payee_amount: u64, reason: &str, fee: u64) -> EscrowResult<()> {
+    if reason.chars().count() > MAX_REASON_CHARS {
+        return Err(EscrowError::InvalidArgument(format!(
+            "reason must be at most {} characters",
+            MAX_REASON_CHARS
+        )));
+    }
+    if contract.splits.is_some() && payee_amount > 0 {
+        return Err(EscrowError::InvalidArgument(
+            "split escrows can only be cancelled without a payout".to_string(),
+        ));
+    }
+    if payee_amount > 0 && payee_amount - contract.fee_on(payee_amount) <= fee {
+        return Err(EscrowError::InvalidArgument(format!(
+            "payee_amount after the platform fee must exceed the ledger fee of {}",
+            fee
+        )));
+    }
+    let unpaid = contract.unpaid_amount();
+    if payee_amount >= unpaid || unpaid - payee_amount <= fee {
+        return Err(EscrowError::InvalidArgument(format!(
+            "what goes back to the payer must exceed the ledger fee of {}",
+            fee
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use crate::escrow::ContractStatus;
+use crate::evidence::EvidenceKind;
+use crate::kyc::KycLevel;
+use crate::limits::LimitKind;
+use crate::pause::PauseScope;
+use crate::template::Industry;
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum EscrowError {
+    NotFound,
+    Unauthorized,
+    InvalidStatus(ContractStatus),
+    InvalidArgument(String),
+    UnsupportedLedger(Principal),
+    HashLockMissing,
+    HashLockExpired,
+    HashLockNotExpired,
+    PreimageRevealed,
+    PreimageMismatch,
+    ApprovalRequired,
+    AlreadyApproved,
+    NotApproved,
+    TemplateNotFound(Industry),
+    EvidenceMissing(Vec<EvidenceKind>),
+    Paused(PauseScope),
+    LimitExceeded(LimitKind),
+    VendorNotFound(String),
+    VendorInactive(String),
+    KycRequired { principal: Principal, level: KycLevel },
+    PayoutPending,
+    ContractBusy,
+    CancellationAgreed,
+    Ledger(String),
+    SystemCall(String),
+    Encoding(String),
+}
+
+pub type EscrowResult<T> = Result<T, EscrowError>;
@@ -0,0 +1,280 @@
+use crate::amendment::{Amendment, AmendmentChange, TermsVersion};
+use crate::cancellation::Cancellation;
+use crate::error::{EscrowError, EscrowResult};
+use crate::evidence::{Evidence, EvidenceKind};
+use crate::hashlock::{HashLock, HashLockArgs};
+use crate::multisig::{ReleaseApprovals, ReleasePolicyArgs};
+use crate::split::{PayeeShare, SplitPayee, SplitTransfer};
+use crate::template::Industry;
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeSet;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const MAX_MILESTONES: usize = 50;
+const MAX_MILESTONE_TITLE_CHARS: usize = 200;
+/// A year; a longer window would leave a delivered escrow open indefinitely.
+pub const MAX_INSPECTION_WINDOW_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContractStatus {
+    Pending,
+    Accepted,
+    Active,
+    Released,
+    Refunded,
+    Disputed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeResolution {
+    ReleaseToPayee,
+    RefundToPayer,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum EscrowEvent {
+    Created,
+    Accepted,
+    Funded { block_index: u64 },
+    Released { block_index: u64 },
+    Refunded { block_index: Option<u64> },
+    Disputed { reason: String },
+    DisputeResolved { resolution: DisputeResolution },
+    DeliveryConfirmed,
+    HashLockExpired,
+    ReleaseApproved,
+    ReleaseApprovalRevoked,
+    SplitPayout { transfers: Vec<SplitTransfer> },
+    EvidenceSubmitted { kind: EvidenceKind },
+    Delivered,
+    FeeCollected { amount: u64, block_index: u64 },
+    CancellationProposed { payee_amount: u64, reason: String },
+    CancellationWithdrawn,
+    CancellationConfirmed { payee_amount: u64 },
+    /// The payee's share under a confirmed cancellation, net of fees.
+    CancellationPayout { amount: u64, block_index: u64 },
+    AmendmentProposed { change: AmendmentChange },
+    AmendmentWithdrawn,
+    /// The change took effect as terms `version`; a top-up carries the block
+    /// of its deposit.
+    AmendmentApproved { version: u32, hash: ByteBuf, block_index: Option<u64> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub actor: Principal,
+    pub event: EscrowEvent,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Milestone {
+    pub title: String,
+    pub due_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EscrowContract {
+    pub id: u64,
+    pub payer: Principal,
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+    pub status: ContractStatus,
+    pub hash_lock: Option<HashLock>,
+    pub release_approvals: Option<ReleaseApprovals>,
+    pub splits: Option<Vec<SplitPayee>>,
+    pub subscription_id: Option<u64>,
+    pub industry: Option<Industry>,
+    pub fee_bps: u32,
+    pub inspection_window_secs: Option<u64>,
+    pub milestones: Vec<Milestone>,
+    pub required_evidence: Vec<EvidenceKind>,
+    pub evidence: Vec<Evidence>,
+    pub delivered_at: Option<u64>,
+    pub vendor_id: Option<String>,
+    /// The vendor's payout account for this ledger at creation time; the
+    /// payee's default account if `None`.
+    pub payee_account: Option<Account>,
+    pub cancellation: Option<Cancellation>,
+    pub amendment: Option<Amendment>,
+    /// Every version of the terms, the one in force last.
+    pub terms_versions: Vec<TermsVersion>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub history: Vec<HistoryEntry>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CreateEscrowArgs {
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+    pub hash_lock: Option<HashLockArgs>,
+    pub release_policy: Option<ReleasePolicyArgs>,
+    pub splits: Option<Vec<PayeeShare>>,
+    pub inspection_window_secs: Option<u64>,
+    pub milestones: Option<Vec<Milestone>>,
+    pub required_evidence: Option<Vec<EvidenceKind>>,
+    /// Handle of the vendor being paid, who must be the payee.
+    pub vendor_id: Option<String>,
+}
+
+impl CreateEscrowArgs {
+    /// Checks the delivery terms: the inspection window, the milestones and
+    /// the evidence the payee has to provide.
+    pub fn validate_delivery_terms(&self, now: u64) -> EscrowResult<()> {
+        if self.inspection_window_secs.is_some_and(|secs| secs > MAX_INSPECTION_WINDOW_SECS) {
+            return Err(EscrowError::InvalidArgument(format!(
+                "inspection window may be at most {} seconds",
+                MAX_INSPECTION_WINDOW_SECS
+            )));
+        }
+        if let Some(milestones) = &self.milestones {
+            validate_milestones(milestones, 0, now)?;
+        }
+        if let Some(kinds) = &self.required_evidence {
+            let unique: BTreeSet<_> = kinds.iter().collect();
+            if unique.len() != kinds.len() {
+                return Err(EscrowError::InvalidArgument("required evidence kinds must be unique".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks `milestones` about to be added to a contract that has `existing`
+/// ones already, whether at creation or through an amendment.
+pub fn validate_milestones(milestones: &[Milestone], existing: usize, now: u64) -> EscrowResult<()> {
+    if existing + milestones.len() > MAX_MILESTONES {
+        return Err(EscrowError::InvalidArgument(format!(
+            "at most {} milestones are allowed",
+            MAX_MILESTONES
+        )));
+    }
+    for milestone in milestones {
+        let title_len = milestone.title.trim().chars().count();
+        if title_len == 0 || title_len > MAX_MILESTONE_TITLE_CHARS {
+            return Err(EscrowError::InvalidArgument(format!(
+                "milestone title must be 1 to {} characters",
+                MAX_MILESTONE_TITLE_CHARS
+            )));
+        }
+        if milestone.due_at.is_some_and(|due_at| due_at <= now) {
+            return Err(EscrowError::InvalidArgument("milestone must be due in the future".to_string()));
+        }
+    }
+    Ok(())
+}
+
+impl EscrowContract {
+    pub fn new(id: u64, payer: Principal, args: CreateEscrowArgs, splits: Option<Vec<SplitPayee>>, now: u64) -> EscrowResult<Self> {
+        let mut contract = EscrowContract {
+            id,
+            payer,
+            payee: args.payee,
+            ledger: args.ledger,
+            amount: args.amount,
+            conditions: args.conditions,
+            status: ContractStatus::Pending,
+            hash_lock: args.hash_lock.map(HashLock::from),
+            release_approvals: args.release_policy.map(ReleaseApprovals::from),
+            splits,
+            subscription_id: None,
+            industry: None,
+            fee_bps: 0,
+            inspection_window_secs: args.inspection_window_secs,
+            milestones: args.milestones.unwrap_or_default(),
+            required_evidence: args.required_evidence.unwrap_or_default(),
+            evidence: Vec::new(),
+            delivered_at: None,
+            vendor_id: args.vendor_id,
+            payee_account: None,
+            cancellation: None,
+            amendment: None,
+            terms_versions: Vec::new(),
+            created_at: now,
+            updated_at: now,
+            history: Vec::new(),
+        };
+        contract.terms_versions.push(TermsVersion::new(1, &contract, now)?);
+        contract.record(payer, EscrowEvent::Created, now);
+        Ok(contract)
+    }
+
+    pub fn is_party(&self, principal: Principal) -> bool {
+        self.payer == principal || self.payee == principal
+    }
+
+    pub fn payee_account(&self) -> Account {
+        self.payee_account.unwrap_or_else(|| Account::from(self.payee))
+    }
+
+    /// What is still held in the escrow subaccount, i.e. the amount minus any
+    /// split shares or cancellation payout that have already been paid out.
+    pub fn unpaid_amount(&self) -> u64 {
+        let paid: u64 = self
+            .splits
+            .iter()
+            .flatten()
+            .filter(|s| s.block_index.is_some())
+            .map(|s| s.amount)
+            .sum();
+        self.amount - paid - self.cancellation.as_ref().map_or(0, Cancellation::paid_out)
+    }
+
+    /// Whether a release approval policy, if the contract has one, is met.
+    pub fn release_approved(&self) -> bool {
+        self.release_approvals.as_ref().is_none_or(|a| a.is_met())
+    }
+
+    pub fn was_funded(&self) -> bool {
+        self.history.iter().any(|h| matches!(h.event, EscrowEvent::Funded { .. }))
+    }
+
+    pub fn cancellation_agreed(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(Cancellation::is_agreed)
+    }
+
+    /// The platform's cut of `amount` under this contract's fee rate.
+    pub fn fee_on(&self, amount: u64) -> u64 {
+        (amount as u128 * self.fee_bps as u128 / 10_000) as u64
+    }
+
+    /// Whether the payer's inspection window after delivery has run out
+    /// without a dispute.
+    pub fn inspection_elapsed(&self, now: u64) -> bool {
+        match (self.delivered_at, self.inspection_window_secs) {
+            (Some(delivered_at), Some(window)) => {
+                now >= delivered_at.saturating_add(window.saturating_mul(NANOS_PER_SEC))
+            }
+            _ => false,
+        }
+    }
+
+    pub fn require_status(&self, status: ContractStatus) -> EscrowResult<()> {
+        if self.status == status {
+            Ok(())
+        } else {
+            Err(EscrowError::InvalidStatus(self.status))
+        }
+    }
+
+    pub fn record(&mut self, actor: Principal, event: EscrowEvent, now: u64) {
+        self.history.push(HistoryEntry {
+            timestamp: now,
+            actor,
+            event,
+        });
+        self.updated_at = now;
+    }
+
+    pub fn transition(&mut self, status: ContractStatus, actor: Principal, event: EscrowEvent, now: u64) {
+        self.status = status;
+        self.record(actor, event, now);
+    }
+}
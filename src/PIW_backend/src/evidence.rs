@@ -0,0 +1,77 @@
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::EscrowContract;
+use crate::limits::LimitKind;
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+use serde_bytes::ByteBuf;
+
+pub const MAX_EVIDENCE_BYTES: usize = 1024 * 1024;
+/// Evidence lives on the heap with the rest of the state, which is written
+/// out whole on every upgrade, so both one contract's evidence and all of it
+/// together are capped regardless of per-principal limits.
+pub const MAX_CONTRACT_EVIDENCE_BYTES: u64 = 20 * 1024 * 1024;
+pub const MAX_STORED_EVIDENCE_BYTES: u64 = 512 * 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EvidenceKind {
+    Photo,
+    Document,
+    ShippingReceipt,
+    InspectionReport,
+    TitleTransfer,
+    DomainTransferConfirmation,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Evidence {
+    pub submitted_by: Principal,
+    pub kind: EvidenceKind,
+    pub description: String,
+    pub data: ByteBuf,
+    pub submitted_at: u64,
+}
+
+impl Evidence {
+    pub fn size(&self) -> u64 {
+        (self.data.len() + self.description.len()) as u64
+    }
+}
+
+pub fn validate(data: &[u8]) -> EscrowResult<()> {
+    if data.len() > MAX_EVIDENCE_BYTES {
+        return Err(EscrowError::InvalidArgument(format!(
+            "evidence may be at most {} bytes",
+            MAX_EVIDENCE_BYTES
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `size` more bytes of evidence fit on `contract` and in the
+/// canister.
+pub fn check_capacity(state: &State, contract: &EscrowContract, size: u64) -> EscrowResult<()> {
+    let on_contract: u64 = contract.evidence.iter().map(Evidence::size).sum();
+    if on_contract + size > MAX_CONTRACT_EVIDENCE_BYTES {
+        return Err(EscrowError::LimitExceeded(LimitKind::EvidenceBytes));
+    }
+    let stored: u64 = state
+        .escrows
+        .values()
+        .flat_map(|c| &c.evidence)
+        .map(Evidence::size)
+        .sum();
+    if stored + size > MAX_STORED_EVIDENCE_BYTES {
+        return Err(EscrowError::LimitExceeded(LimitKind::EvidenceStorage));
+    }
+    Ok(())
+}
+
+/// The required kinds that `submitter` has not provided yet.
+pub fn missing(required: &[EvidenceKind], submitted: &[Evidence], submitter: Principal) -> Vec<EvidenceKind> {
+    required
+        .iter()
+        .filter(|kind| !submitted.iter().any(|e| e.submitted_by == submitter && e.kind == **kind))
+        .copied()
+        .collect()
+}
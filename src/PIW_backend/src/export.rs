@@ -0,0 +1,268 @@
+//! CSV and JSON statements of a principal's escrows for reconciliation. Each
+//! row is one entry of a contract's history, so amounts, fees, status
+//! timestamps and ledger block indices all come straight from the audit
+//! trail.
+//!
+//! Browsers fetch files through `http_request`, which only ever sees the
+//! anonymous caller, so downloads go through short-lived links created by an
+//! authenticated update call.
+
+use crate::escrow::{EscrowContract, EscrowEvent, HistoryEntry};
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+const PAGE_SIZE: usize = 100;
+const LINK_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000;
+const MAX_LINKS_PER_PRINCIPAL: usize = 5;
+const CSV_HEADER: &str = "contract_id,payer,payee,ledger,amount,fee_bps,platform_fee,status,created_at,timestamp,event,actor,recipient,event_amount,block_index,detail";
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// Which history entries to include, by timestamp: `from` inclusive, `to`
+/// exclusive. `cursor` is the `next_cursor` of the previous page.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ExportRange {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExportPage {
+    pub content_type: String,
+    pub body: String,
+    pub next_cursor: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExportLink {
+    pub owner: Principal,
+    pub format: ExportFormat,
+    pub range: ExportRange,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize)]
+struct Row {
+    contract_id: u64,
+    payer: String,
+    payee: String,
+    ledger: String,
+    amount: u64,
+    fee_bps: u32,
+    platform_fee: u64,
+    status: String,
+    created_at: u64,
+    timestamp: u64,
+    event: &'static str,
+    actor: String,
+    recipient: Option<String>,
+    event_amount: Option<u64>,
+    block_index: Option<u64>,
+    detail: Option<String>,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+impl ExportRange {
+    fn contains(&self, timestamp: u64) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+}
+
+fn event_name(event: &EscrowEvent) -> &'static str {
+    match event {
+        EscrowEvent::Created => "Created",
+        EscrowEvent::Accepted => "Accepted",
+        EscrowEvent::Funded { .. } => "Funded",
+        EscrowEvent::Released { .. } => "Released",
+        EscrowEvent::Refunded { .. } => "Refunded",
+        EscrowEvent::Disputed { .. } => "Disputed",
+        EscrowEvent::DisputeResolved { .. } => "DisputeResolved",
+        EscrowEvent::DeliveryConfirmed => "DeliveryConfirmed",
+        EscrowEvent::HashLockExpired => "HashLockExpired",
+        EscrowEvent::ReleaseApproved => "ReleaseApproved",
+        EscrowEvent::ReleaseApprovalRevoked => "ReleaseApprovalRevoked",
+        EscrowEvent::SplitPayout { .. } => "SplitPayout",
+        EscrowEvent::EvidenceSubmitted { .. } => "EvidenceSubmitted",
+        EscrowEvent::Delivered => "Delivered",
+        EscrowEvent::FeeCollected { .. } => "FeeCollected",
+        EscrowEvent::CancellationProposed { .. } => "CancellationProposed",
+        EscrowEvent::CancellationWithdrawn => "CancellationWithdrawn",
+        EscrowEvent::CancellationConfirmed { .. } => "CancellationConfirmed",
+        EscrowEvent::CancellationPayout { .. } => "CancellationPayout",
+        EscrowEvent::AmendmentProposed { .. } => "AmendmentProposed",
+        EscrowEvent::AmendmentWithdrawn => "AmendmentWithdrawn",
+        EscrowEvent::AmendmentApproved { .. } => "AmendmentApproved",
+    }
+}
+
+/// One row per history entry, except split payouts, which get a row per
+/// recipient so every transfer has its own block index.
+fn rows(contract: &EscrowContract, entry: &HistoryEntry) -> Vec<Row> {
+    let row = |recipient: Option<Principal>, event_amount, block_index, detail| Row {
+        contract_id: contract.id,
+        payer: contract.payer.to_text(),
+        payee: contract.payee.to_text(),
+        ledger: contract.ledger.to_text(),
+        amount: contract.amount,
+        fee_bps: contract.fee_bps,
+        platform_fee: contract.fee_on(contract.amount),
+        status: format!("{:?}", contract.status),
+        created_at: contract.created_at,
+        timestamp: entry.timestamp,
+        event: event_name(&entry.event),
+        actor: entry.actor.to_text(),
+        recipient: recipient.map(|p| p.to_text()),
+        event_amount,
+        block_index,
+        detail,
+    };
+    match &entry.event {
+        EscrowEvent::Funded { block_index } => vec![row(None, Some(contract.amount), Some(*block_index), None)],
+        EscrowEvent::Released { block_index } => vec![row(Some(contract.payee), None, Some(*block_index), None)],
+        EscrowEvent::Refunded { block_index } => {
+            let recipient = block_index.map(|_| contract.payer);
+            vec![row(recipient, None, *block_index, None)]
+        }
+        EscrowEvent::FeeCollected { amount, block_index } => vec![row(None, Some(*amount), Some(*block_index), None)],
+        EscrowEvent::SplitPayout { transfers } => transfers
+            .iter()
+            .map(|t| match &t.result {
+                Ok(block_index) => row(Some(t.recipient), Some(t.amount), Some(*block_index), None),
+                Err(e) => row(Some(t.recipient), Some(t.amount), None, Some(e.clone())),
+            })
+            .collect(),
+        EscrowEvent::Disputed { reason } => vec![row(None, None, None, Some(reason.clone()))],
+        EscrowEvent::DisputeResolved { resolution } => vec![row(None, None, None, Some(format!("{:?}", resolution)))],
+        EscrowEvent::EvidenceSubmitted { kind } => vec![row(None, None, None, Some(format!("{:?}", kind)))],
+        EscrowEvent::CancellationProposed { payee_amount, reason } => {
+            vec![row(None, Some(*payee_amount), None, Some(reason.clone()))]
+        }
+        EscrowEvent::CancellationConfirmed { payee_amount } => vec![row(None, Some(*payee_amount), None, None)],
+        EscrowEvent::CancellationPayout { amount, block_index } => {
+            vec![row(Some(contract.payee), Some(*amount), Some(*block_index), None)]
+        }
+        EscrowEvent::AmendmentProposed { change } => vec![row(None, None, None, Some(format!("{:?}", change)))],
+        EscrowEvent::AmendmentApproved { version, hash, block_index } => {
+            let hash: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+            vec![row(None, None, *block_index, Some(format!("version {} {}", version, hash)))]
+        }
+        _ => vec![row(None, None, None, None)],
+    }
+}
+
+/// Quotes a CSV field when needed, and defuses values a spreadsheet would
+/// otherwise evaluate as a formula.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn csv_line(row: &Row) -> String {
+    let opt = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+    [
+        row.contract_id.to_string(),
+        row.payer.clone(),
+        row.payee.clone(),
+        row.ledger.clone(),
+        row.amount.to_string(),
+        row.fee_bps.to_string(),
+        row.platform_fee.to_string(),
+        row.status.clone(),
+        row.created_at.to_string(),
+        row.timestamp.to_string(),
+        row.event.to_string(),
+        row.actor.clone(),
+        row.recipient.clone().unwrap_or_default(),
+        opt(row.event_amount),
+        opt(row.block_index),
+        row.detail.as_deref().map(csv_field).unwrap_or_default(),
+    ]
+    .join(",")
+}
+
+/// One page of `principal`'s contracts, oldest first. Contracts without any
+/// history inside the range are skipped.
+pub fn export(state: &State, principal: Principal, format: ExportFormat, range: &ExportRange) -> ExportPage {
+    let mut contracts = state
+        .escrows
+        .range(range.cursor.unwrap_or(0)..)
+        .map(|(_, c)| c)
+        .filter(|c| c.is_party(principal))
+        .filter(|c| c.history.iter().any(|h| range.contains(h.timestamp)));
+    let page: Vec<&EscrowContract> = contracts.by_ref().take(PAGE_SIZE).collect();
+    let next_cursor = contracts.next().map(|c| c.id);
+
+    let rows: Vec<Row> = page
+        .iter()
+        .flat_map(|c| {
+            c.history
+                .iter()
+                .filter(|h| range.contains(h.timestamp))
+                .flat_map(|h| rows(c, h))
+        })
+        .collect();
+    let body = match format {
+        ExportFormat::Csv => std::iter::once(CSV_HEADER.to_string())
+            .chain(rows.iter().map(csv_line))
+            .map(|line| line + "\r\n")
+            .collect(),
+        ExportFormat::Json => serde_json::to_string(&rows).expect("rows always serialize"),
+    };
+    ExportPage {
+        content_type: format.content_type().to_string(),
+        body,
+        next_cursor,
+    }
+}
+
+/// Stores a download link for `owner`, dropping expired links and the
+/// owner's oldest ones beyond the cap.
+pub fn add_link(state: &mut State, token: String, link: ExportLink, now: u64) {
+    state.export_links.retain(|_, l| l.expires_at > now);
+    let mut own: Vec<(u64, String)> = state
+        .export_links
+        .iter()
+        .filter(|(_, l)| l.owner == link.owner)
+        .map(|(t, l)| (l.expires_at, t.clone()))
+        .collect();
+    own.sort();
+    while own.len() >= MAX_LINKS_PER_PRINCIPAL {
+        let (_, oldest) = own.remove(0);
+        state.export_links.remove(&oldest);
+    }
+    state.export_links.insert(token, link);
+}
+
+pub fn link_expiry(now: u64) -> u64 {
+    now + LINK_TTL_NANOS
+}
+
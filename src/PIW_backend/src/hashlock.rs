@@ -0,0 +1,64 @@
+//! HTLC-style delivery confirmation. The payer stores the SHA-256 of a secret
+//! on the escrow and hands the secret to the recipient out of band; whoever
+//! presents the preimage before `expires_at` releases the funds to the payee.
+//! After expiry the payer can reclaim the funds instead.
+
+use crate::error::{EscrowError, EscrowResult};
+use candid::{CandidType, Deserialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HashLockArgs {
+    pub hash: ByteBuf,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HashLock {
+    pub hash: ByteBuf,
+    pub expires_at: u64,
+    pub preimage: Option<ByteBuf>,
+}
+
+impl From<HashLockArgs> for HashLock {
+    fn from(args: HashLockArgs) -> Self {
+        HashLock {
+            hash: args.hash,
+            expires_at: args.expires_at,
+            preimage: None,
+        }
+    }
+}
+
+impl HashLockArgs {
+    pub fn validate(&self, now: u64) -> EscrowResult<()> {
+        if self.hash.len() != 32 {
+            return Err(EscrowError::InvalidArgument(
+                "hash lock must be a 32-byte SHA-256 digest".to_string(),
+            ));
+        }
+        if self.expires_at <= now {
+            return Err(EscrowError::InvalidArgument(
+                "hash lock expiry must be in the future".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl HashLock {
+    /// Once the preimage is out, delivery is proven and the funds are owed to
+    /// the payee, even if the release only goes through after expiry.
+    pub fn is_revealed(&self) -> bool {
+        self.preimage.is_some()
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub fn matches(&self, preimage: &[u8]) -> bool {
+        Sha256::digest(preimage).as_slice() == self.hash.as_slice()
+    }
+}
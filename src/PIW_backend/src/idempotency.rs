@@ -0,0 +1,108 @@
+//! Idempotency keys for mutating calls. A client that retries after a network
+//! error sends the same key again; the first successful result is kept per
+//! caller and key for a day and handed back to any replay instead of doing
+//! the work twice. Failures are not kept, so a failed call can be retried
+//! under the same key.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+use serde::de::DeserializeOwned;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const KEY_TTL_NANOS: u64 = 24 * 60 * 60 * NANOS_PER_SEC;
+const MAX_KEY_BYTES: usize = 64;
+const MAX_KEYS_PER_CALLER: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CachedResult {
+    pub method: String,
+    /// SHA-256 of the Candid-encoded arguments, so a key reused for a
+    /// different request is caught instead of answered with a stale result.
+    pub fingerprint: ByteBuf,
+    /// The Candid-encoded successful result.
+    pub response: ByteBuf,
+    pub expires_at: u64,
+}
+
+/// The endpoint and arguments a key is being used for.
+pub struct Request {
+    method: &'static str,
+    fingerprint: ByteBuf,
+}
+
+impl Request {
+    pub fn new<A: CandidType>(method: &'static str, args: A) -> Self {
+        let encoded = candid::encode_one(args).unwrap_or_default();
+        Request {
+            method,
+            fingerprint: ByteBuf::from(Sha256::digest(encoded).to_vec()),
+        }
+    }
+}
+
+pub fn validate(key: &str) -> EscrowResult<()> {
+    if key.is_empty() || key.len() > MAX_KEY_BYTES {
+        return Err(EscrowError::InvalidArgument(format!(
+            "idempotency key must be 1 to {} bytes",
+            MAX_KEY_BYTES
+        )));
+    }
+    Ok(())
+}
+
+/// The result kept for `key`, if the caller already made this request with it.
+pub fn replay<T: DeserializeOwned + CandidType>(
+    state: &State,
+    caller: Principal,
+    key: &str,
+    request: &Request,
+    now: u64,
+) -> EscrowResult<Option<T>> {
+    let Some(cached) = state
+        .idempotency
+        .get(&(caller, key.to_string()))
+        .filter(|c| c.expires_at > now)
+    else {
+        return Ok(None);
+    };
+    if cached.method != request.method || cached.fingerprint != request.fingerprint {
+        return Err(EscrowError::InvalidArgument(format!(
+            "idempotency key was already used for a different {} request",
+            cached.method
+        )));
+    }
+    candid::decode_one(&cached.response)
+        .map(Some)
+        .map_err(|e| EscrowError::InvalidArgument(format!("kept result cannot be read: {}", e)))
+}
+
+/// Keeps `result` for replays. A caller at the limit loses their oldest key.
+pub fn record<T: CandidType>(state: &mut State, caller: Principal, key: String, request: Request, result: &T, now: u64) {
+    let Ok(response) = candid::encode_one(result) else {
+        return;
+    };
+    state.idempotency.retain(|_, c| c.expires_at > now);
+    let mut own: Vec<(u64, String)> = state
+        .idempotency
+        .iter()
+        .filter(|((p, _), _)| *p == caller)
+        .map(|((_, k), c)| (c.expires_at, k.clone()))
+        .collect();
+    own.sort();
+    while own.len() >= MAX_KEYS_PER_CALLER {
+        let (_, oldest) = own.remove(0);
+        state.idempotency.remove(&(caller, oldest));
+    }
+    state.idempotency.insert(
+        (caller, key),
+        CachedResult {
+            method: request.method.to_string(),
+            fingerprint: request.fingerprint,
+            response: ByteBuf::from(response),
+            expires_at: now + KEY_TTL_NANOS,
+        },
+    );
+}
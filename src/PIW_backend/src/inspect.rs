@@ -0,0 +1,78 @@
+//! Early rejection of ingress update calls, before they cost the canister
+//! cycles to execute. This is only a filter: the `guard` functions and the
+//! checks inside each endpoint are still what enforce access.
+
+use crate::access::{self, Role};
+use crate::evidence::MAX_EVIDENCE_BYTES;
+use candid::Principal;
+use ic_cdk::api::call::{accept_message, arg_data_raw_size, method_name};
+use ic_cdk::api::caller;
+use ic_cdk_macros::inspect_message;
+
+const MAX_ARG_BYTES: usize = 16 * 1024;
+/// Room for the candid framing and the text fields around the evidence blob.
+const EVIDENCE_ARG_OVERHEAD: usize = 8 * 1024;
+
+/// Methods anyone may call, including the anonymous principal.
+const PUBLIC_METHODS: &[&str] = &["whoami", "http_request"];
+
+const OWNER_METHODS: &[&str] = &[
+    "set_owner",
+    "set_platform_fee",
+    "set_escrow_template",
+    "remove_escrow_template",
+];
+const ADMIN_METHODS: &[&str] = &[
+    "add_ledger",
+    "grant_role",
+    "revoke_role",
+    "list_role_assignments",
+    "pause",
+    "unpause",
+    "set_default_limits",
+    "set_limits_for",
+    "set_cycles_alert",
+    "set_vendor_verified",
+    "set_vendor_suspended",
+    "set_kyc_issuer",
+    "set_kyc_thresholds",
+    "reconcile_reserves",
+    "set_reserve_deficit_threshold",
+];
+const ARBITER_METHODS: &[&str] = &["resolve_dispute"];
+
+fn required_role(method: &str) -> Option<Role> {
+    [
+        (Role::Owner, OWNER_METHODS),
+        (Role::Admin, ADMIN_METHODS),
+        (Role::Arbiter, ARBITER_METHODS),
+    ]
+    .into_iter()
+    .find(|(_, methods)| methods.contains(&method))
+    .map(|(role, _)| role)
+}
+
+fn max_arg_bytes(method: &str) -> usize {
+    match method {
+        "submit_evidence" => MAX_EVIDENCE_BYTES + EVIDENCE_ARG_OVERHEAD,
+        _ => MAX_ARG_BYTES,
+    }
+}
+
+#[inspect_message]
+fn inspect_message() {
+    let method = method_name();
+    let caller = caller();
+    if caller == Principal::anonymous() && !PUBLIC_METHODS.contains(&method.as_str()) {
+        return;
+    }
+    if arg_data_raw_size() > max_arg_bytes(&method) {
+        return;
+    }
+    if let Some(role) = required_role(&method) {
+        if !access::has_role(caller, role) {
+            return;
+        }
+    }
+    accept_message();
+}
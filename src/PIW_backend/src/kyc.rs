@@ -0,0 +1,97 @@
+//! KYC attestations for large escrows. Identity checks happen off-chain at a
+//! trusted issuer, which then calls `attest_kyc` for the verified principal;
+//! the IC signs and authenticates every call, so a call from a trusted issuer
+//! principal is the attestation. Admins choose the issuers and, per ledger,
+//! the amount from which both parties need an attestation.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KycLevel {
+    Basic,
+    Enhanced,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KycAttestation {
+    pub level: KycLevel,
+    pub issuer: Principal,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+/// Escrows of `min_amount` or more on `ledger` need `level` from both parties.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct KycThreshold {
+    pub ledger: Principal,
+    pub min_amount: u64,
+    pub level: KycLevel,
+}
+
+pub fn attest(state: &mut State, issuer: Principal, subject: Principal, level: KycLevel, expires_at: u64, now: u64) -> EscrowResult<()> {
+    if !state.kyc_issuers.contains(&issuer) {
+        return Err(EscrowError::Unauthorized);
+    }
+    if expires_at <= now {
+        return Err(EscrowError::InvalidArgument("expires_at must be in the future".to_string()));
+    }
+    state.kyc.insert(
+        subject,
+        KycAttestation {
+            level,
+            issuer,
+            issued_at: now,
+            expires_at,
+        },
+    );
+    Ok(())
+}
+
+/// Issuers can only withdraw attestations they issued themselves.
+pub fn revoke(state: &mut State, issuer: Principal, subject: Principal) -> EscrowResult<()> {
+    if !state.kyc_issuers.contains(&issuer) {
+        return Err(EscrowError::Unauthorized);
+    }
+    match state.kyc.get(&subject) {
+        Some(a) if a.issuer == issuer => {
+            state.kyc.remove(&subject);
+            Ok(())
+        }
+        Some(_) => Err(EscrowError::Unauthorized),
+        None => Err(EscrowError::NotFound),
+    }
+}
+
+/// The level `principal` currently holds. Attestations lapse when they
+/// expire or when their issuer stops being trusted.
+pub fn valid_level(state: &State, principal: Principal, now: u64) -> Option<KycLevel> {
+    state
+        .kyc
+        .get(&principal)
+        .filter(|a| a.expires_at > now && state.kyc_issuers.contains(&a.issuer))
+        .map(|a| a.level)
+}
+
+/// The highest level any threshold demands for `amount` on `ledger`.
+pub fn required_level(state: &State, ledger: Principal, amount: u64) -> Option<KycLevel> {
+    state
+        .kyc_thresholds
+        .iter()
+        .filter(|t| t.ledger == ledger && amount >= t.min_amount)
+        .map(|t| t.level)
+        .max()
+}
+
+pub fn check(state: &State, payer: Principal, payee: Principal, ledger: Principal, amount: u64, now: u64) -> EscrowResult<()> {
+    let Some(required) = required_level(state, ledger, amount) else {
+        return Ok(());
+    };
+    for party in [payer, payee] {
+        if valid_level(state, party, now).is_none_or(|level| level < required) {
+            return Err(EscrowError::KycRequired { principal: party, level: required });
+        }
+    }
+    Ok(())
+}
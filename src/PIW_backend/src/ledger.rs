@@ -0,0 +1,140 @@
+use crate::error::{EscrowError, EscrowResult};
+use candid::{Nat, Principal};
+use ic_cdk::call;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use std::cell::Cell;
+
+thread_local! {
+    static IN_FLIGHT_TRANSFERS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Counts a transfer as in flight for as long as it is alive. Dropped when
+/// the call returns, and also when a trap in the callback cleans it up.
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        IN_FLIGHT_TRANSFERS.with(|n| n.set(n.get() + 1));
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT_TRANSFERS.with(|n| n.set(n.get() - 1));
+    }
+}
+
+/// Ledger transfers that have been sent but not answered yet.
+pub fn in_flight_transfers() -> u64 {
+    IN_FLIGHT_TRANSFERS.with(|n| n.get())
+}
+
+/// Funds for each escrow are held in their own subaccount of the canister,
+/// derived from the contract id.
+pub fn escrow_subaccount(contract_id: u64) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount[24..].copy_from_slice(&contract_id.to_be_bytes());
+    subaccount
+}
+
+pub fn escrow_account(canister_id: Principal, contract_id: u64) -> Account {
+    Account {
+        owner: canister_id,
+        subaccount: Some(escrow_subaccount(contract_id)),
+    }
+}
+
+fn nat_to_u64(n: Nat) -> EscrowResult<u64> {
+    u64::try_from(n.0).map_err(|_| EscrowError::Ledger("value does not fit in u64".to_string()))
+}
+
+pub async fn fee(ledger: Principal) -> EscrowResult<u64> {
+    let (fee,): (Nat,) = call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| EscrowError::Ledger(format!("icrc1_fee failed: {:?} {}", code, msg)))?;
+    nat_to_u64(fee)
+}
+
+pub async fn decimals(ledger: Principal) -> EscrowResult<u8> {
+    let (decimals,): (u8,) = call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(|(code, msg)| EscrowError::Ledger(format!("icrc1_decimals failed: {:?} {}", code, msg)))?;
+    Ok(decimals)
+}
+
+pub async fn balance_of(ledger: Principal, account: Account) -> EscrowResult<u64> {
+    let (balance,): (Nat,) = call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| {
+            EscrowError::Ledger(format!("icrc1_balance_of failed: {:?} {}", code, msg))
+        })?;
+    nat_to_u64(balance)
+}
+
+/// Pulls `amount` from `from` into `to` using the allowance `from` granted
+/// this canister. Deduplicated by the ledger like `transfer` when a memo and
+/// a creation time are given.
+pub async fn transfer_from(
+    ledger: Principal,
+    from: Account,
+    to: Account,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> EscrowResult<u64> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to,
+        amount: Nat::from(amount),
+        fee: None,
+        memo: memo.map(Memo::from),
+        created_at_time,
+    };
+    let _in_flight = InFlight::start();
+    let (result,): (Result<Nat, TransferFromError>,) = call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, msg)| {
+            EscrowError::Ledger(format!("icrc2_transfer_from failed: {:?} {}", code, msg))
+        })?;
+    let block = match result {
+        Ok(block) | Err(TransferFromError::Duplicate { duplicate_of: block }) => block,
+        Err(e) => return Err(EscrowError::Ledger(format!("{:?}", e))),
+    };
+    nat_to_u64(block)
+}
+
+/// Sends `amount` out of one of the canister's subaccounts. With a memo and
+/// a creation time the ledger deduplicates the transfer, and a repeat returns
+/// the block of the original.
+pub async fn transfer(
+    ledger: Principal,
+    from_subaccount: Subaccount,
+    to: Account,
+    amount: u64,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> EscrowResult<u64> {
+    let args = TransferArg {
+        from_subaccount: Some(from_subaccount),
+        to,
+        fee: None,
+        created_at_time,
+        memo: memo.map(Memo::from),
+        amount: Nat::from(amount),
+    };
+    let _in_flight = InFlight::start();
+    let (result,): (Result<Nat, TransferError>,) = call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| {
+            EscrowError::Ledger(format!("icrc1_transfer failed: {:?} {}", code, msg))
+        })?;
+    let block = match result {
+        Ok(block) | Err(TransferError::Duplicate { duplicate_of: block }) => block,
+        Err(e) => return Err(EscrowError::Ledger(format!("{:?}", e))),
+    };
+    nat_to_u64(block)
+}
@@ -1,16 +1,116 @@
+#![allow(non_snake_case)]
+
 use candid::{CandidType, Principal, Deserialize};
-use ic_cdk::api::caller;
-use ic_cdk_macros::{query, update, init};
-use std::cell::RefCell;
+use ic_cdk::api::{caller, time};
+use ic_cdk::api::management_canister::http_request::{HttpResponse as CanisterHttpResponse, TransformArgs};
+use ic_cdk_macros::{query, update, init, pre_upgrade, post_upgrade};
+use serde_bytes::ByteBuf;
+use std::time::Duration;
 
-thread_local! {
-    static OWNER: RefCell<Option<Principal>> = RefCell::new(None);
-}
+mod access;
+mod amendment;
+mod cancellation;
+mod error;
+mod escrow;
+mod evidence;
+mod export;
+mod hashlock;
+mod idempotency;
+mod inspect;
+mod kyc;
+mod ledger;
+mod limits;
+mod lock;
+mod log;
+mod metrics;
+#[cfg(test)]
+mod mock;
+mod monitoring;
+mod multisig;
+mod notifications;
+mod pause;
+#[cfg(test)]
+mod proptests;
+mod reputation;
+mod reserves;
+mod runtime;
+mod service;
+mod split;
+mod stable;
+mod state;
+mod subscription;
+mod template;
+mod transfers;
+mod vendor;
+
+use access::{caller_can_read_logs, caller_is_admin, caller_is_arbiter, caller_is_auditor, caller_is_owner, Role};
+use amendment::{AmendmentChange, TermsVersion};
+use error::{EscrowError, EscrowResult};
+use escrow::{CreateEscrowArgs, DisputeResolution, EscrowContract};
+use evidence::EvidenceKind;
+use export::{ExportFormat, ExportLink, ExportPage, ExportRange};
+use idempotency::Request;
+use kyc::{KycAttestation, KycLevel, KycThreshold};
+use limits::Limits;
+use log::{LogFilter, LogPage};
+use monitoring::{CanisterStatus, CyclesAlertConfig};
+use notifications::Notification;
+use pause::{PauseInfo, PauseScope};
+use reputation::{Rating, Reputation};
+use reserves::ReserveReport;
+use runtime::{IcClock, IcLedger};
+use service::Service;
+use stable::StableState;
+use subscription::{CreateSubscriptionArgs, Subscription};
+use template::{EscrowTemplate, Industry};
+use transfers::PendingTransfer;
+use vendor::{Vendor, VendorArgs};
+
+const HASH_LOCK_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SUBSCRIPTION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const CYCLES_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const RESERVES_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const TRANSFER_RETRY_INTERVAL: Duration = Duration::from_secs(30);
 
 #[init]
 fn init() {
-    OWNER.with(|owner| {
-        *owner.borrow_mut() = Some(caller());
+    state::mutate(|s| s.owner = Some(caller()));
+    start_timers();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    state::read(|s| ic_cdk::storage::stable_save((StableState::of(s),))).expect("failed to save state");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Releases before the escrow lifecycle kept nothing in stable memory, not
+    // even the owner, so they start afresh like a new install.
+    if ic_cdk::api::stable::stable_size() == 0 {
+        state::mutate(|s| s.owner = Some(caller()));
+    } else {
+        let (saved,): (StableState,) = ic_cdk::storage::stable_restore().expect("failed to restore state");
+        state::replace(saved.into_state());
+    }
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(HASH_LOCK_SWEEP_INTERVAL, || {
+        ic_cdk::spawn(async { service().refund_expired_hash_locks().await })
+    });
+    ic_cdk_timers::set_timer_interval(SUBSCRIPTION_SWEEP_INTERVAL, || {
+        ic_cdk::spawn(async { service().open_due_subscription_periods().await })
+    });
+    ic_cdk_timers::set_timer_interval(CYCLES_CHECK_INTERVAL, || ic_cdk::spawn(monitoring::check_cycles()));
+    ic_cdk_timers::set_timer_interval(TRANSFER_RETRY_INTERVAL, || {
+        ic_cdk::spawn(async { service().retry_pending_transfers().await })
+    });
+    ic_cdk_timers::set_timer_interval(RESERVES_CHECK_INTERVAL, || {
+        ic_cdk::spawn(async {
+            service().reconcile_reserves().await;
+        })
     });
 }
 
@@ -21,16 +121,510 @@ fn whoami() -> Principal {
 
 #[query]
 fn get_owner() -> Option<Principal> {
-    OWNER.with(|owner| *owner.borrow())
+    state::read(|s| s.owner)
 }
 
-#[update]
+#[update(guard = "caller_is_owner")]
 fn set_owner(new_owner: Principal) {
-    OWNER.with(|owner| {
-        *owner.borrow_mut() = Some(new_owner);
+    state::mutate(|s| s.owner = Some(new_owner));
+}
+
+/// Halts one kind of money movement until `unpause` is called for it.
+#[update(guard = "caller_is_admin")]
+fn pause(scope: PauseScope, reason: String) {
+    let info = PauseInfo {
+        scope,
+        reason,
+        paused_by: caller(),
+        paused_at: time(),
+    };
+    state::mutate(|s| s.paused.insert(scope, info));
+}
+
+#[update(guard = "caller_is_admin")]
+fn unpause(scope: PauseScope) {
+    state::mutate(|s| s.paused.remove(&scope));
+}
+
+#[query]
+fn get_pause_status() -> Vec<PauseInfo> {
+    state::read(|s| s.paused.values().cloned().collect())
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_default_limits(limits: Limits) {
+    state::mutate(|s| s.default_limits = limits);
+}
+
+/// Gives `principal` its own limits, e.g. higher ones for a verified user.
+/// Passing `None` puts them back on the defaults.
+#[update(guard = "caller_is_admin")]
+fn set_limits_for(principal: Principal, limits: Option<Limits>) {
+    state::mutate(|s| match limits {
+        Some(limits) => s.limit_overrides.insert(principal, limits),
+        None => s.limit_overrides.remove(&principal),
     });
 }
 
+#[query]
+fn get_my_limits() -> Limits {
+    state::read(|s| limits::effective(s, caller()))
+}
+
+#[query(guard = "caller_is_auditor")]
+fn get_canister_status() -> CanisterStatus {
+    state::read(monitoring::status)
+}
+
+/// Runs the reserve reconciliation now instead of waiting for the timer.
+#[update(guard = "caller_is_admin")]
+async fn reconcile_reserves() -> ReserveReport {
+    service().reconcile_reserves().await
+}
+
+/// Payouts sent but not yet confirmed by the ledger, with their retry state.
+#[query(guard = "caller_is_auditor")]
+fn list_pending_transfers() -> Vec<PendingTransfer> {
+    state::read(|s| s.pending_transfers.values().cloned().collect())
+}
+
+#[query(guard = "caller_is_auditor")]
+fn get_reserve_report() -> Option<ReserveReport> {
+    state::read(|s| s.reserve_report.clone())
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_reserve_deficit_threshold(ledger: Principal, max_deficit: u64) {
+    state::mutate(|s| s.reserve_deficit_thresholds.insert(ledger, max_deficit));
+}
+
+#[query(guard = "caller_can_read_logs")]
+fn get_logs(filter: LogFilter, cursor: Option<u64>) -> LogPage {
+    state::read(|s| log::page(s, &filter, cursor))
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_cycles_alert(config: CyclesAlertConfig) -> EscrowResult<()> {
+    if config.webhook_url.as_ref().is_some_and(|url| !url.starts_with("https://")) {
+        return Err(EscrowError::InvalidArgument("webhook_url must be an https URL".to_string()));
+    }
+    state::mutate(|s| {
+        s.cycles_alert = config;
+        s.low_cycles_alerted = false;
+    });
+    Ok(())
+}
+
+/// Transform for the low cycles webhook; called by the system on the reply.
+#[query]
+fn transform_webhook_response(args: TransformArgs) -> CanisterHttpResponse {
+    monitoring::transform_webhook_response(args)
+}
+
+#[query]
+fn my_roles() -> Vec<Role> {
+    access::roles_of(caller())
+}
+
+/// Admins may hand out the operational roles; only the owner can appoint
+/// admins. Ownership itself moves with `set_owner`.
+fn check_can_assign(role: Role) -> EscrowResult<()> {
+    match role {
+        Role::Owner => Err(EscrowError::InvalidArgument("use set_owner to transfer ownership".to_string())),
+        Role::Admin if !access::has_role(caller(), Role::Owner) => Err(EscrowError::Unauthorized),
+        _ => Ok(()),
+    }
+}
+
+#[update(guard = "caller_is_admin")]
+fn grant_role(principal: Principal, role: Role) -> EscrowResult<()> {
+    check_can_assign(role)?;
+    state::mutate(|s| s.roles.entry(principal).or_default().insert(role));
+    Ok(())
+}
+
+#[update(guard = "caller_is_admin")]
+fn revoke_role(principal: Principal, role: Role) -> EscrowResult<()> {
+    check_can_assign(role)?;
+    state::mutate(|s| {
+        let granted = s.roles.get_mut(&principal).ok_or(EscrowError::NotFound)?;
+        if !granted.remove(&role) {
+            return Err(EscrowError::NotFound);
+        }
+        if granted.is_empty() {
+            s.roles.remove(&principal);
+        }
+        Ok(())
+    })
+}
+
+#[query(guard = "caller_is_admin")]
+fn list_role_assignments() -> Vec<(Principal, Vec<Role>)> {
+    state::read(|s| {
+        s.roles
+            .iter()
+            .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+            .collect()
+    })
+}
+
+/// Registers an ICRC-2 ledger that escrows may be denominated in.
+#[update(guard = "caller_is_admin")]
+async fn add_ledger(ledger: Principal) -> EscrowResult<()> {
+    service().add_ledger(ledger).await
+}
+
+#[update(guard = "caller_is_owner")]
+fn set_platform_fee(fee_bps: u32) -> EscrowResult<()> {
+    if fee_bps > 10_000 {
+        return Err(EscrowError::InvalidArgument("fee_bps may not exceed 10000".to_string()));
+    }
+    state::mutate(|s| s.platform_fee_bps = fee_bps);
+    Ok(())
+}
+
+#[update(guard = "caller_is_owner")]
+fn set_escrow_template(template: EscrowTemplate) -> EscrowResult<()> {
+    template.validate()?;
+    state::mutate(|s| s.templates.insert(template.industry, template));
+    Ok(())
+}
+
+#[update(guard = "caller_is_owner")]
+fn remove_escrow_template(industry: Industry) -> EscrowResult<()> {
+    state::mutate(|s| s.templates.remove(&industry))
+        .map(|_| ())
+        .ok_or(EscrowError::TemplateNotFound(industry))
+}
+
+#[query]
+fn list_escrow_templates() -> Vec<EscrowTemplate> {
+    state::read(|s| s.templates.values().cloned().collect())
+}
+
+fn service() -> Service<IcLedger, IcClock> {
+    Service {
+        ledger: IcLedger,
+        clock: IcClock,
+        canister_id: ic_cdk::id(),
+    }
+}
+
+#[update]
+fn create_escrow(args: CreateEscrowArgs, idempotency_key: Option<String>) -> EscrowResult<u64> {
+    let request = Request::new("create_escrow", &args);
+    service().idempotent(caller(), idempotency_key, request, || service().create_escrow(caller(), args))
+}
+
+#[update]
+fn create_escrow_from_template(industry: Industry, args: CreateEscrowArgs, idempotency_key: Option<String>) -> EscrowResult<u64> {
+    let request = Request::new("create_escrow_from_template", (industry, &args));
+    service().idempotent(caller(), idempotency_key, request, || {
+        service().create_escrow_from_template(caller(), industry, args)
+    })
+}
+
+#[update]
+fn accept_escrow(contract_id: u64) -> EscrowResult<()> {
+    service().accept_escrow(caller(), contract_id)
+}
+
+#[update]
+async fn fund_escrow(contract_id: u64, idempotency_key: Option<String>) -> EscrowResult<u64> {
+    let request = Request::new("fund_escrow", contract_id);
+    service()
+        .idempotent_async(caller(), idempotency_key, request, service().fund_escrow(caller(), contract_id))
+        .await
+}
+
+#[update]
+async fn release_funds(contract_id: u64, idempotency_key: Option<String>) -> EscrowResult<u64> {
+    let request = Request::new("release_funds", contract_id);
+    service()
+        .idempotent_async(caller(), idempotency_key, request, service().release_funds(caller(), contract_id))
+        .await
+}
+
+#[update]
+async fn approve_release(contract_id: u64) -> EscrowResult<Option<u64>> {
+    service().approve_release(caller(), contract_id).await
+}
+
+#[update]
+fn revoke_release_approval(contract_id: u64) -> EscrowResult<()> {
+    service().revoke_release_approval(caller(), contract_id)
+}
+
+#[update]
+async fn refund_funds(contract_id: u64, idempotency_key: Option<String>) -> EscrowResult<Option<u64>> {
+    let request = Request::new("refund_funds", contract_id);
+    service()
+        .idempotent_async(caller(), idempotency_key, request, service().refund_funds(caller(), contract_id))
+        .await
+}
+
+#[update]
+fn propose_cancellation(contract_id: u64, payee_amount: u64, reason: String) -> EscrowResult<()> {
+    service().propose_cancellation(caller(), contract_id, payee_amount, reason)
+}
+
+#[update]
+fn withdraw_cancellation(contract_id: u64) -> EscrowResult<()> {
+    service().withdraw_cancellation(caller(), contract_id)
+}
+
+#[update]
+async fn confirm_cancellation(contract_id: u64, payee_amount: u64) -> EscrowResult<u64> {
+    service().confirm_cancellation(caller(), contract_id, payee_amount).await
+}
+
+#[update]
+fn propose_amendment(contract_id: u64, change: AmendmentChange) -> EscrowResult<()> {
+    service().propose_amendment(caller(), contract_id, change)
+}
+
+#[update]
+fn withdraw_amendment(contract_id: u64) -> EscrowResult<()> {
+    service().withdraw_amendment(caller(), contract_id)
+}
+
+#[update]
+async fn approve_amendment(contract_id: u64, change: AmendmentChange) -> EscrowResult<TermsVersion> {
+    service().approve_amendment(caller(), contract_id, change).await
+}
+
+#[update]
+fn submit_evidence(contract_id: u64, kind: EvidenceKind, description: String, data: ByteBuf) -> EscrowResult<()> {
+    service().submit_evidence(caller(), contract_id, kind, description, data)
+}
+
+#[update]
+fn mark_delivered(contract_id: u64) -> EscrowResult<()> {
+    service().mark_delivered(caller(), contract_id)
+}
+
+#[update]
+async fn release_after_inspection(contract_id: u64) -> EscrowResult<u64> {
+    service().release_after_inspection(caller(), contract_id).await
+}
+
+#[update]
+fn dispute_contract(contract_id: u64, reason: String) -> EscrowResult<()> {
+    service().dispute_contract(caller(), contract_id, reason)
+}
+
+#[update(guard = "caller_is_arbiter")]
+async fn resolve_dispute(contract_id: u64, resolution: DisputeResolution) -> EscrowResult<u64> {
+    service().resolve_dispute(caller(), contract_id, resolution).await
+}
+
+#[update]
+fn rate_counterparty(contract_id: u64, stars: u8, review: String) -> EscrowResult<()> {
+    service().rate_counterparty(caller(), contract_id, stars, review)
+}
+
+/// Track record of `principal`, for vetting a counterparty before contracting.
+#[query]
+fn get_reputation(principal: Principal) -> Reputation {
+    state::read(|s| reputation::reputation(s, principal))
+}
+
+#[query]
+fn get_ratings(principal: Principal) -> Vec<Rating> {
+    state::read(|s| reputation::received(s, principal))
+}
+
+#[update]
+async fn confirm_delivery(contract_id: u64, preimage: ByteBuf) -> EscrowResult<u64> {
+    service().confirm_delivery(caller(), contract_id, preimage).await
+}
+
+#[update]
+async fn reclaim_expired_escrow(contract_id: u64) -> EscrowResult<u64> {
+    service().reclaim_expired_escrow(caller(), contract_id).await
+}
+
+#[update]
+fn create_subscription(args: CreateSubscriptionArgs, idempotency_key: Option<String>) -> EscrowResult<u64> {
+    let request = Request::new("create_subscription", &args);
+    service().idempotent(caller(), idempotency_key, request, || service().create_subscription(caller(), args))
+}
+
+#[update]
+fn accept_subscription(subscription_id: u64) -> EscrowResult<()> {
+    service().accept_subscription(caller(), subscription_id)
+}
+
+#[update]
+fn cancel_subscription(subscription_id: u64) -> EscrowResult<()> {
+    service().cancel_subscription(caller(), subscription_id)
+}
+
+#[query]
+fn get_subscription(subscription_id: u64) -> Option<Subscription> {
+    let caller = caller();
+    state::read(|s| s.subscriptions.get(&subscription_id).cloned())
+        .filter(|sub| sub.payer == caller || sub.payee == caller)
+}
+
+#[query]
+fn list_my_subscriptions() -> Vec<Subscription> {
+    let caller = caller();
+    state::read(|s| {
+        s.subscriptions
+            .values()
+            .filter(|sub| sub.payer == caller || sub.payee == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+fn get_contract(contract_id: u64) -> Option<EscrowContract> {
+    let caller = caller();
+    state::read(|s| s.escrows.get(&contract_id).cloned())
+        .filter(|c| c.is_party(caller) || access::can_view_all(caller))
+}
+
+/// The terms in force at `at`, or now if not given, for disputes that need
+/// to know what was agreed at a certain time.
+#[query]
+fn get_terms(contract_id: u64, at: Option<u64>) -> EscrowResult<Option<TermsVersion>> {
+    let caller = caller();
+    let Some(contract) = state::read(|s| s.escrows.get(&contract_id).cloned())
+        .filter(|c| c.is_party(caller) || access::can_view_all(caller))
+    else {
+        return Ok(None);
+    };
+    amendment::in_force_at(&contract, at.unwrap_or_else(time))
+}
+
+#[query]
+fn list_my_contracts() -> Vec<EscrowContract> {
+    let caller = caller();
+    state::read(|s| s.escrows.values().filter(|c| c.is_party(caller)).cloned().collect())
+}
+
+/// A page of the caller's escrow history as CSV or JSON, for bookkeeping.
+#[query]
+fn export_my_escrows(format: ExportFormat, range: ExportRange) -> ExportPage {
+    state::read(|s| export::export(s, caller(), format, &range))
+}
+
+/// Returns a token for downloading the same export from a browser at
+/// `/export/<token>`; it expires after 15 minutes.
+#[update]
+async fn create_export_link(format: ExportFormat, range: ExportRange) -> EscrowResult<String> {
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(EscrowError::Unauthorized);
+    }
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| EscrowError::SystemCall(format!("raw_rand failed: {:?} {}", code, msg)))?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let now = time();
+    let link = ExportLink {
+        owner,
+        format,
+        range,
+        expires_at: export::link_expiry(now),
+    };
+    state::mutate(|s| export::add_link(s, token.clone(), link, now));
+    Ok(token)
+}
+
+#[update]
+fn register_vendor(args: VendorArgs) -> EscrowResult<()> {
+    service().register_vendor(caller(), args)
+}
+
+#[update]
+fn update_vendor(args: VendorArgs) -> EscrowResult<()> {
+    service().update_vendor(caller(), args)
+}
+
+#[update]
+fn set_vendor_active(active: bool) -> EscrowResult<()> {
+    service().set_vendor_active(caller(), active)
+}
+
+#[query]
+fn get_vendor(handle: String) -> Option<Vendor> {
+    state::read(|s| s.vendors.get(&handle).cloned())
+}
+
+#[query]
+fn get_my_vendor() -> Option<Vendor> {
+    state::read(|s| vendor::by_principal(s, caller()).cloned())
+}
+
+/// Grants or withdraws the verification badge after checking the vendor's
+/// business details off-chain.
+#[update(guard = "caller_is_admin")]
+fn set_vendor_verified(handle: String, verified: bool) -> EscrowResult<()> {
+    state::mutate(|s| vendor::set_verified(s, &handle, verified, time()))
+}
+
+/// A suspended vendor cannot be named in new escrows; open ones carry on.
+#[update(guard = "caller_is_admin")]
+fn set_vendor_suspended(handle: String, suspended: bool) -> EscrowResult<()> {
+    state::mutate(|s| vendor::set_suspended(s, &handle, suspended, time()))
+}
+
+/// Trusts or stops trusting `issuer` to attest KYC. Attestations from an
+/// issuer that is no longer trusted stop counting.
+#[update(guard = "caller_is_admin")]
+fn set_kyc_issuer(issuer: Principal, trusted: bool) {
+    state::mutate(|s| {
+        if trusted {
+            s.kyc_issuers.insert(issuer);
+        } else {
+            s.kyc_issuers.remove(&issuer);
+        }
+    });
+}
+
+#[update(guard = "caller_is_admin")]
+fn set_kyc_thresholds(thresholds: Vec<KycThreshold>) {
+    state::mutate(|s| s.kyc_thresholds = thresholds);
+}
+
+#[query]
+fn get_kyc_thresholds() -> Vec<KycThreshold> {
+    state::read(|s| s.kyc_thresholds.clone())
+}
+
+/// Called by a trusted issuer once it has verified `subject`.
+#[update]
+fn attest_kyc(subject: Principal, level: KycLevel, expires_at: u64) -> EscrowResult<()> {
+    state::mutate(|s| kyc::attest(s, caller(), subject, level, expires_at, time()))
+}
+
+#[update]
+fn revoke_kyc(subject: Principal) -> EscrowResult<()> {
+    state::mutate(|s| kyc::revoke(s, caller(), subject))
+}
+
+#[query]
+fn get_my_kyc() -> Option<KycAttestation> {
+    state::read(|s| s.kyc.get(&caller()).cloned())
+}
+
+#[query]
+fn get_my_notifications() -> Vec<Notification> {
+    state::read(|s| s.notifications.get(&caller()).cloned().unwrap_or_default())
+}
+
+#[update]
+fn mark_notification_as_read(notification_id: u64) -> EscrowResult<()> {
+    if state::mutate(|s| notifications::mark_as_read(s, caller(), notification_id)) {
+        Ok(())
+    } else {
+        Err(EscrowError::NotFound)
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
     method: String,
@@ -46,8 +640,46 @@ struct HttpResponse {
     body: Vec<u8>,
 }
 
+/// Serves an export link; `?cursor=` fetches the following pages, whose start
+/// is given in the `X-Next-Cursor` header.
+fn export_download(token: &str, url: &str) -> HttpResponse {
+    let Some(link) = state::read(|s| s.export_links.get(token).cloned()).filter(|l| l.expires_at > time()) else {
+        return HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"export link not found or expired".to_vec(),
+        };
+    };
+    let mut range = link.range;
+    if let Some(cursor) = url
+        .split_once('?')
+        .and_then(|(_, query)| query.split('&').find_map(|p| p.strip_prefix("cursor=")))
+        .and_then(|c| c.parse().ok())
+    {
+        range.cursor = Some(cursor);
+    }
+    let page = state::read(|s| export::export(s, link.owner, link.format, &range));
+    let mut headers = vec![
+        ("Content-Type".to_string(), page.content_type),
+        (
+            "Content-Disposition".to_string(),
+            format!("attachment; filename=\"escrows.{}\"", link.format.extension()),
+        ),
+        ("Cache-Control".to_string(), "no-store".to_string()),
+    ];
+    if let Some(next) = page.next_cursor {
+        headers.push(("X-Next-Cursor".to_string(), next.to_string()));
+    }
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: page.body.into_bytes(),
+    }
+}
+
 #[query]
 fn http_request(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
     // Check if the request is for Internet Identity authentication
     if req.url.contains("authenticate") {
         // Handle the Internet Identity authentication request
@@ -56,6 +688,21 @@ fn http_request(req: HttpRequest) -> HttpResponse {
             headers: vec![("Location".to_string(), "https://identity.ic0.app/".to_string())],
             body: vec![],
         }
+    } else if req.method == "GET" && path == "/metrics" {
+        match state::read(metrics::encode) {
+            Ok(body) => HttpResponse {
+                status_code: 200,
+                headers: vec![("Content-Type".to_string(), "text/plain; version=0.0.4".to_string())],
+                body,
+            },
+            Err(e) => HttpResponse {
+                status_code: 500,
+                headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+                body: format!("failed to encode metrics: {}", e).into_bytes(),
+            },
+        }
+    } else if req.method == "GET" && path.starts_with("/export/") {
+        export_download(&path["/export/".len()..], &req.url)
     } else {
         // Handle other requests
         HttpResponse {
@@ -64,4 +711,4 @@ fn http_request(req: HttpRequest) -> HttpResponse {
             body: b"Hello, World!".to_vec(),
         }
     }
-}
\ No newline at end of file
+}
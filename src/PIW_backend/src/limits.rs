@@ -0,0 +1,77 @@
+//! Per-principal quotas that keep one caller from flooding the canister.
+//! Admins set the defaults and can raise them for individual principals.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::ContractStatus;
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+
+const HOUR_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_open_escrows: u32,
+    pub max_creations_per_hour: u32,
+    pub max_evidence_bytes_per_dispute: u64,
+    /// Notifications are written on behalf of their recipient, so going over
+    /// never fails a call: the oldest ones make room instead.
+    pub max_notifications: u32,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_open_escrows: 50,
+            max_creations_per_hour: 20,
+            max_evidence_bytes_per_dispute: 5 * 1024 * 1024,
+            max_notifications: 500,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    OpenEscrows,
+    CreationsPerHour,
+    EvidenceBytes,
+    /// The canister holds as much evidence as it can.
+    EvidenceStorage,
+}
+
+pub fn effective(state: &State, principal: Principal) -> Limits {
+    state
+        .limit_overrides
+        .get(&principal)
+        .cloned()
+        .unwrap_or_else(|| state.default_limits.clone())
+}
+
+/// Checks the open-escrow and hourly creation quotas before `payer` opens
+/// another escrow or subscription.
+pub fn check_creation(state: &State, payer: Principal, now: u64) -> EscrowResult<()> {
+    let limits = effective(state, payer);
+    let open = state
+        .escrows
+        .values()
+        .filter(|c| c.payer == payer)
+        .filter(|c| !matches!(c.status, ContractStatus::Released | ContractStatus::Refunded))
+        .count();
+    if open >= limits.max_open_escrows as usize {
+        return Err(EscrowError::LimitExceeded(LimitKind::OpenEscrows));
+    }
+    let recent = state
+        .recent_creations
+        .get(&payer)
+        .map(|times| times.iter().filter(|t| **t + HOUR_NANOS > now).count())
+        .unwrap_or(0);
+    if recent >= limits.max_creations_per_hour as usize {
+        return Err(EscrowError::LimitExceeded(LimitKind::CreationsPerHour));
+    }
+    Ok(())
+}
+
+pub fn record_creation(state: &mut State, payer: Principal, now: u64) {
+    let times = state.recent_creations.entry(payer).or_default();
+    times.retain(|t| *t + HOUR_NANOS > now);
+    times.push(now);
+}
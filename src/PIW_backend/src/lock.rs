@@ -0,0 +1,37 @@
+//! Per-contract locks for the async operations that move money. Between a
+//! ledger call and its reply other messages run, and one of them could start
+//! a second payout of the same contract from a status that is not updated
+//! yet. The lock is taken before the first `await` and held until the
+//! operation is done. Locks live outside the stable state on purpose: an
+//! upgrade cannot happen with calls outstanding, so none survive one.
+
+use crate::error::{EscrowError, EscrowResult};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+thread_local! {
+    static LOCKED: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Holds the lock on one contract while alive. Dropped when the operation
+/// returns, and also when a trap in a callback cleans the call up, so a
+/// failed operation never leaves its contract locked.
+#[must_use]
+pub struct ContractLock {
+    contract_id: u64,
+}
+
+impl ContractLock {
+    pub fn acquire(contract_id: u64) -> EscrowResult<Self> {
+        if !LOCKED.with(|l| l.borrow_mut().insert(contract_id)) {
+            return Err(EscrowError::ContractBusy);
+        }
+        Ok(ContractLock { contract_id })
+    }
+}
+
+impl Drop for ContractLock {
+    fn drop(&mut self) {
+        LOCKED.with(|l| l.borrow_mut().remove(&self.contract_id));
+    }
+}
@@ -0,0 +1,88 @@
+//! Bounded, queryable log kept in canister state, so it is saved to stable
+//! memory with everything else on upgrade. Each entry is also printed to the
+//! replica log as before.
+
+use crate::state::{self, State};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{caller, time};
+
+const MAX_ENTRIES: usize = 2_000;
+const PAGE_SIZE: usize = 100;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub caller: Principal,
+    pub contract_id: Option<u64>,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LogFilter {
+    pub min_level: Option<LogLevel>,
+    pub caller: Option<Principal>,
+    pub contract_id: Option<u64>,
+    pub since: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogPage {
+    pub entries: Vec<LogEntry>,
+    pub next_cursor: Option<u64>,
+}
+
+impl LogFilter {
+    fn matches(&self, entry: &LogEntry) -> bool {
+        self.min_level.is_none_or(|level| entry.level >= level)
+            && self.caller.is_none_or(|c| entry.caller == c)
+            && self.contract_id.is_none_or(|id| entry.contract_id == Some(id))
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+    }
+}
+
+pub fn log(level: LogLevel, contract_id: Option<u64>, message: String) {
+    let (entry_caller, now) = (caller(), time());
+    state::mutate(|s| append(s, level, entry_caller, contract_id, message, now));
+}
+
+/// Adds an entry for `caller`, dropping the oldest once the buffer is full.
+pub fn append(state: &mut State, level: LogLevel, caller: Principal, contract_id: Option<u64>, message: String, now: u64) {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::println!("[{:?}] {}", level, message);
+    let id = state.next_log_id;
+    state.next_log_id += 1;
+    state.logs.push_back(LogEntry {
+        id,
+        timestamp: now,
+        level,
+        caller,
+        contract_id,
+        message,
+    });
+    while state.logs.len() > MAX_ENTRIES {
+        state.logs.pop_front();
+    }
+}
+
+/// Returns matching entries, oldest first, starting at entry id `cursor`.
+pub fn page(state: &State, filter: &LogFilter, cursor: Option<u64>) -> LogPage {
+    let start = cursor.unwrap_or(0);
+    let mut matching = state
+        .logs
+        .iter()
+        .filter(|e| e.id >= start)
+        .filter(|e| filter.matches(e));
+    let entries: Vec<LogEntry> = matching.by_ref().take(PAGE_SIZE).cloned().collect();
+    let next_cursor = matching.next().map(|e| e.id);
+    LogPage { entries, next_cursor }
+}
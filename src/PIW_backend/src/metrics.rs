@@ -0,0 +1,86 @@
+//! Prometheus text exposition for `GET /metrics`.
+
+use crate::escrow::ContractStatus;
+use crate::ledger;
+use crate::monitoring;
+use crate::state::State;
+use ic_metrics_encoder::MetricsEncoder;
+use std::collections::BTreeMap;
+
+const STATUSES: [(ContractStatus, &str); 6] = [
+    (ContractStatus::Pending, "pending"),
+    (ContractStatus::Accepted, "accepted"),
+    (ContractStatus::Active, "active"),
+    (ContractStatus::Released, "released"),
+    (ContractStatus::Refunded, "refunded"),
+    (ContractStatus::Disputed, "disputed"),
+];
+
+pub fn encode(state: &State) -> std::io::Result<Vec<u8>> {
+    let now_millis = (ic_cdk::api::time() / 1_000_000) as i64;
+    let mut w = MetricsEncoder::new(Vec::new(), now_millis);
+
+    let mut escrows = w.gauge_vec("piw_escrows", "Number of escrow contracts by status.")?;
+    for (status, label) in STATUSES {
+        let count = state.escrows.values().filter(|c| c.status == status).count();
+        escrows = escrows.value(&[("status", label)], count as f64)?;
+    }
+
+    let mut locked: BTreeMap<String, u64> = state
+        .ledgers
+        .keys()
+        .map(|ledger| (ledger.to_text(), 0))
+        .collect();
+    for contract in state
+        .escrows
+        .values()
+        .filter(|c| matches!(c.status, ContractStatus::Active | ContractStatus::Disputed))
+    {
+        *locked.entry(contract.ledger.to_text()).or_default() += contract.unpaid_amount();
+    }
+    let mut locked_gauge = w.gauge_vec(
+        "piw_locked_amount",
+        "Funds held in active and disputed escrows, in the ledger's base units.",
+    )?;
+    for (ledger, amount) in &locked {
+        locked_gauge = locked_gauge.value(&[("ledger", ledger)], *amount as f64)?;
+    }
+
+    w.encode_gauge(
+        "piw_pending_ledger_transfers",
+        state.pending_transfers.len() as f64,
+        "Payouts queued and not yet confirmed by the ledger.",
+    )?;
+    w.encode_gauge(
+        "piw_in_flight_ledger_transfers",
+        ledger::in_flight_transfers() as f64,
+        "Ledger transfers sent and not yet answered.",
+    )?;
+    let unread = state
+        .notifications
+        .values()
+        .flatten()
+        .filter(|n| !n.read)
+        .count();
+    w.encode_gauge(
+        "piw_unread_notifications",
+        unread as f64,
+        "Notifications stored and not yet read.",
+    )?;
+    w.encode_gauge(
+        "piw_cycles_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "Cycles balance of the canister.",
+    )?;
+    w.encode_gauge(
+        "piw_heap_memory_bytes",
+        monitoring::heap_memory_bytes() as f64,
+        "Size of the wasm heap in bytes.",
+    )?;
+    w.encode_gauge(
+        "piw_stable_memory_bytes",
+        monitoring::stable_memory_bytes() as f64,
+        "Size of stable memory in bytes.",
+    )?;
+    Ok(w.into_inner())
+}
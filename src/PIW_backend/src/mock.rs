@@ -0,0 +1,247 @@
+//! In-memory stand-ins for the ledger and the clock, so the escrow logic can
+//! be exercised with plain `cargo test`.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::runtime::{Clock, Ledger};
+use candid::Principal;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Drives a future that never has to wait, which holds for everything built
+/// on the mocks below.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future is waiting on something other than the mocks"),
+    }
+}
+
+/// Polls a future once, for tests that interleave calls.
+pub fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        Poll::Pending
+    }
+}
+
+pub struct MockClock {
+    now: Cell<u64>,
+}
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock { now: Cell::new(now) }
+    }
+
+    pub fn advance_secs(&self, secs: u64) {
+        self.now.set(self.now.get() + secs * NANOS_PER_SEC);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// A single-token ICRC-2 ledger. Fees are burned, so `balances` plus
+/// `burned` never changes except through `mint`.
+pub struct MockLedger {
+    pub fee: u64,
+    pub decimals: u8,
+    /// The canister under test, i.e. the only spender allowances are for.
+    pub spender: Principal,
+    balances: RefCell<BTreeMap<Account, u64>>,
+    allowances: RefCell<BTreeMap<Account, u64>>,
+    burned: Cell<u64>,
+    next_block: Cell<u64>,
+    /// Successful transfers out of each of the canister's subaccounts to
+    /// someone other than the canister itself.
+    payouts: RefCell<BTreeMap<Subaccount, u32>>,
+    /// Makes every transfer fail, as if the ledger were unreachable.
+    pub fail: Cell<bool>,
+    /// Executes transfers but reports them as failed, as if the reply had
+    /// been lost on the way back.
+    pub lose_replies: Cell<bool>,
+    /// Block of each deduplicated transfer, keyed by everything the real
+    /// ledger compares.
+    seen: RefCell<BTreeMap<DedupKey, u64>>,
+    /// Makes the next transfer wait once before it executes, as if the call
+    /// were still on its way, so a test can run another message meanwhile.
+    pub suspend: Cell<bool>,
+}
+
+type DedupKey = (Account, Account, u64, Vec<u8>, u64);
+
+impl MockLedger {
+    pub fn new(fee: u64, spender: Principal) -> Self {
+        MockLedger {
+            fee,
+            decimals: 8,
+            spender,
+            balances: RefCell::default(),
+            allowances: RefCell::default(),
+            burned: Cell::new(0),
+            next_block: Cell::new(0),
+            payouts: RefCell::default(),
+            fail: Cell::new(false),
+            lose_replies: Cell::new(false),
+            seen: RefCell::default(),
+            suspend: Cell::new(false),
+        }
+    }
+
+    pub fn mint(&self, to: Account, amount: u64) {
+        *self.balances.borrow_mut().entry(to).or_default() += amount;
+    }
+
+    /// `icrc2_approve` for the canister; costs `owner` one fee like the real
+    /// ledger does.
+    pub fn approve(&self, owner: Principal, amount: u64) -> EscrowResult<()> {
+        let owner = Account::from(owner);
+        self.debit(owner, self.fee)?;
+        self.burned.set(self.burned.get() + self.fee);
+        self.allowances.borrow_mut().insert(owner, amount);
+        Ok(())
+    }
+
+    /// Destroys funds held by `from`, e.g. to fake a shortfall in an escrow.
+    pub fn burn(&self, from: Account, amount: u64) -> EscrowResult<()> {
+        self.debit(from, amount)?;
+        self.burned.set(self.burned.get() + amount);
+        Ok(())
+    }
+
+    pub fn balance(&self, account: Account) -> u64 {
+        self.balances.borrow().get(&account).copied().unwrap_or(0)
+    }
+
+    /// Everything ever minted: balances plus burned fees.
+    pub fn total_supply(&self) -> u64 {
+        self.balances.borrow().values().sum::<u64>() + self.burned.get()
+    }
+
+    pub fn payouts_from(&self, subaccount: Subaccount) -> u32 {
+        self.payouts.borrow().get(&subaccount).copied().unwrap_or(0)
+    }
+
+    fn debit(&self, from: Account, amount: u64) -> EscrowResult<()> {
+        let mut balances = self.balances.borrow_mut();
+        let balance = balances.entry(from).or_default();
+        if *balance < amount {
+            return Err(EscrowError::Ledger(format!("InsufficientFunds {{ balance: {} }}", balance)));
+        }
+        *balance -= amount;
+        Ok(())
+    }
+
+    fn available(&self) -> EscrowResult<()> {
+        if self.fail.get() {
+            return Err(EscrowError::Ledger("ledger unavailable".to_string()));
+        }
+        Ok(())
+    }
+
+    fn move_funds(&self, from: Account, to: Account, amount: u64) -> EscrowResult<u64> {
+        self.available()?;
+        self.debit(from, amount + self.fee)?;
+        self.burned.set(self.burned.get() + self.fee);
+        *self.balances.borrow_mut().entry(to).or_default() += amount;
+        let block = self.next_block.get();
+        self.next_block.set(block + 1);
+        Ok(block)
+    }
+}
+
+impl Ledger for MockLedger {
+    async fn fee(&self, _ledger: Principal) -> EscrowResult<u64> {
+        Ok(self.fee)
+    }
+
+    async fn decimals(&self, _ledger: Principal) -> EscrowResult<u8> {
+        Ok(self.decimals)
+    }
+
+    async fn balance_of(&self, _ledger: Principal, account: Account) -> EscrowResult<u64> {
+        Ok(self.balance(account))
+    }
+
+    async fn transfer_from(
+        &self,
+        _ledger: Principal,
+        from: Account,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64> {
+        self.available()?;
+        let key = memo.zip(created_at_time).map(|(memo, time)| (from, to, amount, memo, time));
+        if let Some(block) = key.as_ref().and_then(|k| self.seen.borrow().get(k).copied()) {
+            return Ok(block);
+        }
+        let allowance = self.allowances.borrow().get(&from).copied().unwrap_or(0);
+        if allowance < amount + self.fee {
+            return Err(EscrowError::Ledger(format!("InsufficientAllowance {{ allowance: {} }}", allowance)));
+        }
+        let block = self.move_funds(from, to, amount)?;
+        self.allowances.borrow_mut().insert(from, allowance - amount - self.fee);
+        if let Some(key) = key {
+            self.seen.borrow_mut().insert(key, block);
+        }
+        Ok(block)
+    }
+
+    async fn transfer(
+        &self,
+        _ledger: Principal,
+        from_subaccount: Subaccount,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64> {
+        if self.suspend.replace(false) {
+            YieldOnce(false).await;
+        }
+        // An unreachable ledger does not answer duplicates either.
+        self.available()?;
+        let from = Account {
+            owner: self.spender,
+            subaccount: Some(from_subaccount),
+        };
+        let key = memo.zip(created_at_time).map(|(memo, time)| (from, to, amount, memo, time));
+        if let Some(block) = key.as_ref().and_then(|k| self.seen.borrow().get(k).copied()) {
+            return Ok(block);
+        }
+        let block = self.move_funds(from, to, amount)?;
+        if to.owner != self.spender {
+            *self.payouts.borrow_mut().entry(from_subaccount).or_default() += 1;
+        }
+        if let Some(key) = key {
+            self.seen.borrow_mut().insert(key, block);
+        }
+        if self.lose_replies.get() {
+            return Err(EscrowError::Ledger("reply lost".to_string()));
+        }
+        Ok(block)
+    }
+}
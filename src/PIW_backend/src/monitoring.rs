@@ -0,0 +1,217 @@
+//! Cycles and resource monitoring. A timer samples the cycles balance so the
+//! status query can report burn rates, and alerts admins when the balance
+//! drops below the configured floor.
+
+use crate::access::Role;
+use crate::log::{self, LogLevel};
+use crate::notifications;
+use crate::state::{self, State};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::time;
+
+const MAX_CYCLES_SAMPLES: usize = 150;
+const MAX_CALL_COSTS: usize = 50;
+const WEBHOOK_CYCLES: u128 = 30_000_000_000;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 2_048;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// Windows the status query reports a burn rate for: one hour and one day.
+const BURN_RATE_WINDOWS_SECS: [u64; 2] = [60 * 60, 24 * 60 * 60];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesSample {
+    pub timestamp: u64,
+    pub balance: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CallCost {
+    pub method: String,
+    pub instructions: u64,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CyclesAlertConfig {
+    pub floor: u128,
+    pub webhook_url: Option<String>,
+}
+
+impl Default for CyclesAlertConfig {
+    fn default() -> Self {
+        CyclesAlertConfig {
+            floor: 1_000_000_000_000,
+            webhook_url: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BurnRate {
+    pub window_secs: u64,
+    pub cycles_per_hour: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterStatus {
+    pub cycles_balance: u128,
+    pub cycles_floor: u128,
+    pub burn_rates: Vec<BurnRate>,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub recent_call_costs: Vec<CallCost>,
+}
+
+pub fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+fn instructions_used() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::performance_counter(1)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable_size() * 65536
+}
+
+/// Remembers how many instructions the current call has used so far, across
+/// all of its awaits. Called at the end of the expensive endpoints.
+pub fn record_call_cost(method: &str, now: u64) {
+    let instructions = instructions_used();
+    state::mutate(|s| {
+        s.call_costs.push(CallCost {
+            method: method.to_string(),
+            instructions,
+            timestamp: now,
+        });
+        if s.call_costs.len() > MAX_CALL_COSTS {
+            s.call_costs.remove(0);
+        }
+    });
+}
+
+fn burn_rate(samples: &[CyclesSample], window_secs: u64, now: u64) -> Option<BurnRate> {
+    let latest = samples.last()?;
+    let since = now.saturating_sub(window_secs * NANOS_PER_SEC);
+    let oldest = samples.iter().find(|s| s.timestamp >= since)?;
+    let elapsed = latest.timestamp.checked_sub(oldest.timestamp).filter(|e| *e > 0)?;
+    let burned = oldest.balance.saturating_sub(latest.balance);
+    Some(BurnRate {
+        window_secs,
+        cycles_per_hour: burned * 3600 * NANOS_PER_SEC as u128 / elapsed as u128,
+    })
+}
+
+pub fn status(state: &State) -> CanisterStatus {
+    let now = time();
+    CanisterStatus {
+        cycles_balance: ic_cdk::api::canister_balance128(),
+        cycles_floor: state.cycles_alert.floor,
+        burn_rates: BURN_RATE_WINDOWS_SECS
+            .iter()
+            .filter_map(|w| burn_rate(&state.cycles_samples, *w, now))
+            .collect(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_memory_bytes(),
+        recent_call_costs: state.call_costs.clone(),
+    }
+}
+
+pub fn admins(state: &State) -> Vec<Principal> {
+    state
+        .owner
+        .into_iter()
+        .chain(
+            state
+                .roles
+                .iter()
+                .filter(|(_, roles)| roles.contains(&Role::Admin))
+                .map(|(p, _)| *p),
+        )
+        .collect()
+}
+
+/// Samples the balance and, the first time it is seen below the floor,
+/// notifies every admin and calls the webhook if one is configured. The
+/// alert re-arms once the balance is back above the floor.
+pub async fn check_cycles() {
+    let balance = ic_cdk::api::canister_balance128();
+    let now = time();
+    let alert = state::mutate(|s| {
+        s.cycles_samples.push(CyclesSample { timestamp: now, balance });
+        if s.cycles_samples.len() > MAX_CYCLES_SAMPLES {
+            s.cycles_samples.remove(0);
+        }
+        let floor = s.cycles_alert.floor;
+        if balance >= floor {
+            s.low_cycles_alerted = false;
+            return None;
+        }
+        if s.low_cycles_alerted {
+            return None;
+        }
+        s.low_cycles_alerted = true;
+        let message = format!("Cycles balance {} is below the floor of {}", balance, floor);
+        for admin in admins(s) {
+            notifications::notify(s, admin, message.clone(), None, now);
+        }
+        Some((s.cycles_alert.webhook_url.clone(), floor))
+    });
+    if let Some((Some(url), floor)) = alert {
+        if let Err(e) = send_webhook(url, balance, floor).await {
+            log::log(LogLevel::Warn, None, format!("low cycles webhook failed: {}", e));
+        }
+    }
+}
+
+/// Reduces a webhook reply to its status. Headers such as `Date` differ
+/// between the replicas making the request, and the body is never read, so
+/// keeping either would only stop the replicas from agreeing on the reply.
+pub fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+async fn send_webhook(url: String, balance: u128, floor: u128) -> Result<(), String> {
+    let body = format!(
+        "{{\"canister\":\"{}\",\"cycles_balance\":{},\"cycles_floor\":{}}}",
+        ic_cdk::id(),
+        balance,
+        floor
+    );
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(body.into_bytes()),
+        transform: Some(TransformContext::from_name("transform_webhook_response".to_string(), vec![])),
+    };
+    http_request(request, WEBHOOK_CYCLES)
+        .await
+        .map(|_| ())
+        .map_err(|(code, msg)| format!("{:?} {}", code, msg))
+}
@@ -0,0 +1,80 @@
+//! m-of-n release approvals. When an escrow carries a policy, the payer can
+//! no longer release on their own; instead `threshold` of the named approvers
+//! must sign off, and the payout runs as soon as the last approval lands.
+
+use crate::error::{EscrowError, EscrowResult};
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeSet;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReleasePolicyArgs {
+    pub approvers: Vec<Principal>,
+    pub threshold: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReleaseApprovals {
+    pub approvers: BTreeSet<Principal>,
+    pub threshold: u32,
+    pub approved_by: BTreeSet<Principal>,
+}
+
+impl ReleasePolicyArgs {
+    pub fn validate(&self) -> EscrowResult<()> {
+        let unique: BTreeSet<_> = self.approvers.iter().collect();
+        if unique.len() != self.approvers.len() {
+            return Err(EscrowError::InvalidArgument("approvers must be unique".to_string()));
+        }
+        if self.threshold == 0 || self.threshold as usize > self.approvers.len() {
+            return Err(EscrowError::InvalidArgument(
+                "threshold must be between 1 and the number of approvers".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<ReleasePolicyArgs> for ReleaseApprovals {
+    fn from(args: ReleasePolicyArgs) -> Self {
+        ReleaseApprovals {
+            approvers: args.approvers.into_iter().collect(),
+            threshold: args.threshold,
+            approved_by: BTreeSet::new(),
+        }
+    }
+}
+
+impl ReleaseApprovals {
+    pub fn is_met(&self) -> bool {
+        self.approved_by.len() >= self.threshold as usize
+    }
+
+    /// Adds `approver`'s signature. Once the threshold is met further calls
+    /// are accepted without changing anything, so a payout that failed on
+    /// the ledger can be retried by any approver.
+    pub fn approve(&mut self, approver: Principal) -> EscrowResult<()> {
+        if !self.approvers.contains(&approver) {
+            return Err(EscrowError::Unauthorized);
+        }
+        if self.is_met() {
+            return Ok(());
+        }
+        if !self.approved_by.insert(approver) {
+            return Err(EscrowError::AlreadyApproved);
+        }
+        Ok(())
+    }
+
+    pub fn revoke(&mut self, approver: Principal) -> EscrowResult<()> {
+        if !self.approvers.contains(&approver) {
+            return Err(EscrowError::Unauthorized);
+        }
+        if self.is_met() {
+            return Err(EscrowError::InvalidArgument("approval threshold already met".to_string()));
+        }
+        if !self.approved_by.remove(&approver) {
+            return Err(EscrowError::NotApproved);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use crate::limits;
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub id: u64,
+    pub message: String,
+    pub contract_id: Option<u64>,
+    pub timestamp: u64,
+    pub read: bool,
+}
+
+/// Stores a notification for `user`. Once they hit their quota the oldest
+/// read notification makes room, or the oldest overall if all are unread.
+pub fn notify(state: &mut State, user: Principal, message: String, contract_id: Option<u64>, now: u64) {
+    let id = state.next_notification_id;
+    state.next_notification_id += 1;
+    let max = limits::effective(state, user).max_notifications as usize;
+    let list = state.notifications.entry(user).or_default();
+    while !list.is_empty() && list.len() >= max {
+        let oldest = list.iter().position(|n| n.read).unwrap_or(0);
+        list.remove(oldest);
+    }
+    if max == 0 {
+        return;
+    }
+    list.push(Notification {
+        id,
+        message,
+        contract_id,
+        timestamp: now,
+        read: false,
+    });
+}
+
+pub fn mark_as_read(state: &mut State, user: Principal, notification_id: u64) -> bool {
+    state
+        .notifications
+        .get_mut(&user)
+        .and_then(|list| list.iter_mut().find(|n| n.id == notification_id))
+        .map(|n| n.read = true)
+        .is_some()
+}
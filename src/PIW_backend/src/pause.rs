@@ -0,0 +1,31 @@
+//! Circuit breaker for money movement. Each scope can be halted on its own so
+//! that, say, releases can be frozen while refunds keep flowing. Queries are
+//! never affected.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::state;
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PauseScope {
+    Creation,
+    Funding,
+    Releases,
+    Refunds,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PauseInfo {
+    pub scope: PauseScope,
+    pub reason: String,
+    pub paused_by: Principal,
+    pub paused_at: u64,
+}
+
+pub fn check(scope: PauseScope) -> EscrowResult<()> {
+    if state::read(|s| s.paused.contains_key(&scope)) {
+        Err(EscrowError::Paused(scope))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Random sequences of escrow operations from random callers against the
+//! mock ledger, checking after every step that:
+//! - no tokens are created or destroyed,
+//! - no contract pays out more than once, or twice when a cancellation
+//!   splits it between the parties,
+//! - released and refunded contracts never change status again,
+//! - each escrow subaccount holds exactly what the contract has locked,
+//!   unless a payout for it is queued and may or may not have left.
+
+use crate::cancellation::Cancellation;
+use crate::error::EscrowResult;
+use crate::escrow::{ContractStatus, CreateEscrowArgs, DisputeResolution};
+use crate::ledger;
+use crate::mock::{block_on, MockClock, MockLedger};
+use crate::service::Service;
+use crate::state::{self, State};
+use crate::transfers;
+use candid::Principal;
+use icrc_ledger_types::icrc1::account::Account;
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+const FEE: u64 = 10;
+const INITIAL_BALANCE: u64 = 10_000_000;
+const USERS: u8 = 3;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Create { payer: u8, payee: u8, amount: u64 },
+    Accept { caller: u8, contract: u64 },
+    Fund { caller: u8, contract: u64 },
+    Release { caller: u8, contract: u64 },
+    Refund { caller: u8, contract: u64 },
+    Dispute { caller: u8, contract: u64 },
+    Resolve { contract: u64, refund: bool },
+    ProposeCancellation { caller: u8, contract: u64, payee_amount: u64 },
+    ConfirmCancellation { caller: u8, contract: u64 },
+    LedgerOutage(bool),
+    LostReplies(bool),
+    RetryTimer,
+}
+
+fn user(n: u8) -> Principal {
+    Principal::from_slice(&[n + 1; 29])
+}
+
+fn token() -> Principal {
+    Principal::from_slice(&[200; 29])
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let caller = 0..USERS;
+    // Contract ids start at 1; the odd unknown id is fine.
+    let contract = 1..8u64;
+    prop_oneof![
+        3 => (0..USERS, 0..USERS, 1..1_000_000u64)
+            .prop_map(|(payer, payee, amount)| Op::Create { payer, payee, amount }),
+        2 => (caller.clone(), contract.clone()).prop_map(|(caller, contract)| Op::Accept { caller, contract }),
+        2 => (caller.clone(), contract.clone()).prop_map(|(caller, contract)| Op::Fund { caller, contract }),
+        2 => (caller.clone(), contract.clone()).prop_map(|(caller, contract)| Op::Release { caller, contract }),
+        2 => (caller.clone(), contract.clone()).prop_map(|(caller, contract)| Op::Refund { caller, contract }),
+        1 => (caller.clone(), contract.clone()).prop_map(|(caller, contract)| Op::Dispute { caller, contract }),
+        1 => (contract.clone(), any::<bool>()).prop_map(|(contract, refund)| Op::Resolve { contract, refund }),
+        1 => (caller.clone(), contract.clone(), 0..1_000_000u64)
+            .prop_map(|(caller, contract, payee_amount)| Op::ProposeCancellation { caller, contract, payee_amount }),
+        1 => (caller, contract).prop_map(|(caller, contract)| Op::ConfirmCancellation { caller, contract }),
+        1 => any::<bool>().prop_map(Op::LedgerOutage),
+        1 => any::<bool>().prop_map(Op::LostReplies),
+        1 => Just(Op::RetryTimer),
+    ]
+}
+
+fn setup(platform_fee_bps: u32) -> Service<MockLedger, MockClock> {
+    state::replace(State::default());
+    state::mutate(|s| s.platform_fee_bps = platform_fee_bps);
+    let canister_id = Principal::from_slice(&[100; 29]);
+    let service = Service {
+        ledger: MockLedger::new(FEE, canister_id),
+        clock: MockClock::new(1_700_000_000_000_000_000),
+        canister_id,
+    };
+    block_on(service.add_ledger(token())).unwrap();
+    for n in 0..USERS {
+        service.ledger.mint(Account::from(user(n)), INITIAL_BALANCE);
+        service.ledger.approve(user(n), u64::MAX / 2).unwrap();
+    }
+    service
+}
+
+/// Applies `op`; errors are expected and are part of what is being tested.
+fn apply(service: &Service<MockLedger, MockClock>, op: &Op) {
+    let _: EscrowResult<()> = match *op {
+        Op::Create { payer, payee, amount } => {
+            let args = CreateEscrowArgs {
+                payee: user(payee),
+                ledger: token(),
+                amount,
+                conditions: String::new(),
+                hash_lock: None,
+                release_policy: None,
+                splits: None,
+                inspection_window_secs: None,
+                milestones: None,
+                required_evidence: None,
+                vendor_id: None,
+            };
+            service.create_escrow(user(payer), args).map(drop)
+        }
+        Op::Accept { caller, contract } => service.accept_escrow(user(caller), contract),
+        Op::Fund { caller, contract } => block_on(service.fund_escrow(user(caller), contract)).map(drop),
+        Op::Release { caller, contract } => block_on(service.release_funds(user(caller), contract)).map(drop),
+        Op::Refund { caller, contract } => block_on(service.refund_funds(user(caller), contract)).map(drop),
+        Op::Dispute { caller, contract } => service.dispute_contract(user(caller), contract, String::new()),
+        Op::Resolve { contract, refund } => {
+            let resolution = if refund {
+                DisputeResolution::RefundToPayer
+            } else {
+                DisputeResolution::ReleaseToPayee
+            };
+            block_on(service.resolve_dispute(user(USERS), contract, resolution)).map(drop)
+        }
+        Op::LedgerOutage(down) => {
+            service.ledger.fail.set(down);
+            Ok(())
+        }
+        Op::ProposeCancellation { caller, contract, payee_amount } => {
+            service.propose_cancellation(user(caller), contract, payee_amount, String::new())
+        }
+        Op::ConfirmCancellation { caller, contract } => {
+            // Confirms whatever is on the table, so the amounts line up.
+            let proposed = state::read(|s| s.escrows.get(&contract)?.cancellation.as_ref().map(|c| c.payee_amount));
+            block_on(service.confirm_cancellation(user(caller), contract, proposed.unwrap_or(0))).map(drop)
+        }
+        Op::LostReplies(lost) => {
+            service.ledger.lose_replies.set(lost);
+            Ok(())
+        }
+        Op::RetryTimer => {
+            service.clock.advance_secs(60 * 60);
+            block_on(service.retry_pending_transfers());
+            Ok(())
+        }
+    };
+}
+
+fn check_invariants(service: &Service<MockLedger, MockClock>, finished: &mut BTreeMap<u64, ContractStatus>) {
+    let supply = INITIAL_BALANCE * USERS as u64;
+    assert_eq!(service.ledger.total_supply(), supply, "tokens were created or destroyed");
+    state::read(|s| {
+        for contract in s.escrows.values() {
+            let subaccount = ledger::escrow_subaccount(contract.id);
+            let payouts = service.ledger.payouts_from(subaccount);
+            let shared = contract.cancellation.as_ref().is_some_and(|c| c.is_agreed() && c.payee_amount > 0);
+            let allowed = if shared { 2 } else { 1 };
+            assert!(payouts <= allowed, "contract {} paid out {} times", contract.id, payouts);
+
+            if let Some(status) = finished.get(&contract.id) {
+                assert_eq!(contract.status, *status, "contract {} left a terminal state", contract.id);
+            }
+            if matches!(contract.status, ContractStatus::Released | ContractStatus::Refunded) {
+                finished.insert(contract.id, contract.status);
+            }
+
+            // A platform fee too small to cover the transfer fee is left
+            // behind rather than collected.
+            let fee_left = |amount| Some(contract.fee_on(amount)).filter(|fee| *fee <= FEE).unwrap_or(0);
+            let locked = match contract.status {
+                ContractStatus::Active | ContractStatus::Disputed => contract.unpaid_amount(),
+                ContractStatus::Released => fee_left(contract.amount),
+                ContractStatus::Refunded => fee_left(contract.cancellation.as_ref().map_or(0, Cancellation::paid_out)),
+                _ => 0,
+            };
+            if !transfers::pending_for(s, contract.id).is_empty() {
+                continue;
+            }
+            let balance = service.ledger.balance(ledger::escrow_account(service.canister_id, contract.id));
+            assert_eq!(balance, locked, "contract {} in {:?} holds {}", contract.id, contract.status, balance);
+        }
+    });
+}
+
+proptest! {
+    #[test]
+    fn escrow_invariants_hold(
+        platform_fee_bps in prop_oneof![Just(0u32), Just(250u32)],
+        ops in prop::collection::vec(op(), 1..60)
+    ) {
+        let service = setup(platform_fee_bps);
+        let mut finished = BTreeMap::new();
+        for op in &ops {
+            apply(&service, op);
+            check_invariants(&service, &mut finished);
+        }
+    }
+
+    #[test]
+    fn a_cancellation_settles_exactly_once(
+        platform_fee_bps in prop_oneof![Just(0u32), Just(250u32)],
+        amount in 10_000..1_000_000u64,
+        // Tiny shares and tiny remainders are where transfers stop paying
+        // for their fee.
+        share_kind in 0..3u8,
+        small in 0..40u64,
+        share_pct in 0..100u64,
+        faults in prop::collection::vec(0..3u8, 0..4)
+    ) {
+        let service = setup(platform_fee_bps);
+        let (payer, payee) = (user(0), user(1));
+        let args = CreateEscrowArgs {
+            payee,
+            ledger: token(),
+            amount,
+            conditions: String::new(),
+            hash_lock: None,
+            release_policy: None,
+            splits: None,
+            inspection_window_secs: None,
+            milestones: None,
+            required_evidence: None,
+            vendor_id: None,
+        };
+        let id = service.create_escrow(payer, args).unwrap();
+        service.accept_escrow(payee, id).unwrap();
+        block_on(service.fund_escrow(payer, id)).unwrap();
+        let payee_before = service.ledger.balance(Account::from(payee));
+        let share = match share_kind {
+            0 => small,
+            1 => amount - small,
+            _ => amount * share_pct / 100,
+        };
+        if service.propose_cancellation(payee, id, share, String::new()).is_err() {
+            // Turned away up front rather than agreed and stuck.
+            let contract = state::read(|s| s.escrows[&id].clone());
+            prop_assert!(contract.cancellation.is_none());
+            prop_assert!(share > 0 && share - contract.fee_on(share) <= FEE || amount - share <= FEE);
+            return Ok(());
+        }
+
+        let mut finished = BTreeMap::new();
+        for fault in faults {
+            service.ledger.fail.set(fault == 1);
+            service.ledger.lose_replies.set(fault == 2);
+            let _ = block_on(service.confirm_cancellation(payer, id, share));
+            check_invariants(&service, &mut finished);
+            service.clock.advance_secs(60 * 60);
+            block_on(service.retry_pending_transfers());
+            check_invariants(&service, &mut finished);
+        }
+        service.ledger.fail.set(false);
+        service.ledger.lose_replies.set(false);
+        if state::read(|s| s.escrows[&id].status) == ContractStatus::Active {
+            let _ = block_on(service.confirm_cancellation(payer, id, share));
+        }
+        check_invariants(&service, &mut finished);
+
+        let contract = state::read(|s| s.escrows[&id].clone());
+        prop_assert_eq!(contract.status, ContractStatus::Refunded);
+        let net_share = if share > 0 { share - contract.fee_on(share) - FEE } else { 0 };
+        prop_assert_eq!(service.ledger.balance(Account::from(payee)), payee_before + net_share);
+    }
+}
@@ -0,0 +1,143 @@
+//! Ratings the two parties leave each other once a contract is settled, and
+//! the per-principal track record built from them and from the escrow store.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::{ContractStatus, EscrowEvent};
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeMap;
+
+pub const MAX_REVIEW_CHARS: usize = 500;
+
+/// Decimals of ledgers registered before they were recorded (ICP, ckBTC).
+const DEFAULT_DECIMALS: u8 = 8;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Rating {
+    pub contract_id: u64,
+    pub rater: Principal,
+    pub ratee: Principal,
+    pub stars: u8,
+    pub review: String,
+    pub created_at: u64,
+}
+
+/// Settled volume on one ledger in whole tokens, coarsened so the exact
+/// amount stays private.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumeBand {
+    None,
+    /// Under 10 tokens.
+    Low,
+    /// Under 1,000 tokens.
+    Medium,
+    /// Under 100,000 tokens.
+    High,
+    VeryHigh,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerVolume {
+    pub ledger: Principal,
+    pub band: VolumeBand,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Reputation {
+    pub principal: Principal,
+    /// Contracts released to the payee.
+    pub completed: u64,
+    pub refunded: u64,
+    /// Settled contracts that went through a dispute on the way.
+    pub disputed: u64,
+    /// `disputed` out of all settled contracts, in basis points.
+    pub dispute_rate_bps: u32,
+    pub rating_count: u64,
+    pub average_rating: Option<f64>,
+    pub volume: Vec<LedgerVolume>,
+}
+
+impl VolumeBand {
+    fn of(amount: u64, decimals: u8) -> Self {
+        let token = 10u128.checked_pow(decimals as u32).unwrap_or(u128::MAX);
+        let tokens = |n: u128| n.saturating_mul(token);
+        match amount as u128 {
+            0 => VolumeBand::None,
+            a if a < tokens(10) => VolumeBand::Low,
+            a if a < tokens(1_000) => VolumeBand::Medium,
+            a if a < tokens(100_000) => VolumeBand::High,
+            _ => VolumeBand::VeryHigh,
+        }
+    }
+}
+
+pub fn validate(stars: u8, review: &str) -> EscrowResult<()> {
+    if !(1..=5).contains(&stars) {
+        return Err(EscrowError::InvalidArgument("stars must be between 1 and 5".to_string()));
+    }
+    if review.chars().count() > MAX_REVIEW_CHARS {
+        return Err(EscrowError::InvalidArgument(format!(
+            "review may be at most {} characters",
+            MAX_REVIEW_CHARS
+        )));
+    }
+    Ok(())
+}
+
+/// Ratings `principal` has received, newest first.
+pub fn received(state: &State, principal: Principal) -> Vec<Rating> {
+    let mut ratings: Vec<Rating> = state
+        .ratings
+        .values()
+        .flatten()
+        .filter(|r| r.ratee == principal)
+        .cloned()
+        .collect();
+    ratings.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    ratings
+}
+
+pub fn reputation(state: &State, principal: Principal) -> Reputation {
+    let (mut completed, mut refunded, mut disputed) = (0u64, 0u64, 0u64);
+    let mut volume: BTreeMap<Principal, u64> = BTreeMap::new();
+    // Unfunded contracts that were called off never settled anything.
+    for contract in state.escrows.values().filter(|c| c.is_party(principal) && c.was_funded()) {
+        match contract.status {
+            ContractStatus::Released => {
+                completed += 1;
+                *volume.entry(contract.ledger).or_default() += contract.amount;
+            }
+            ContractStatus::Refunded => refunded += 1,
+            _ => continue,
+        }
+        if contract.history.iter().any(|h| matches!(h.event, EscrowEvent::Disputed { .. })) {
+            disputed += 1;
+        }
+    }
+    let settled = completed + refunded;
+    let dispute_rate_bps = (disputed * 10_000).checked_div(settled).unwrap_or(0) as u32;
+
+    let ratings = received(state, principal);
+    let average_rating = (!ratings.is_empty())
+        .then(|| ratings.iter().map(|r| r.stars as f64).sum::<f64>() / ratings.len() as f64);
+
+    Reputation {
+        principal,
+        completed,
+        refunded,
+        disputed,
+        dispute_rate_bps,
+        rating_count: ratings.len() as u64,
+        average_rating,
+        volume: volume
+            .into_iter()
+            .map(|(ledger, amount)| {
+                let decimals = state.ledgers.get(&ledger).and_then(|l| l.decimals);
+                LedgerVolume {
+                    ledger,
+                    band: VolumeBand::of(amount, decimals.unwrap_or(DEFAULT_DECIMALS)),
+                }
+            })
+            .collect(),
+    }
+}
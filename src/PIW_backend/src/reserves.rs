@@ -0,0 +1,47 @@
+//! Proof of reserves: a timer compares what every open escrow should hold
+//! with what its subaccount actually holds on the ledger. A deficit on a
+//! ledger above the admin-set threshold pauses releases and refunds until an
+//! admin has looked into it.
+
+use crate::escrow::{ContractStatus, EscrowContract};
+use candid::{CandidType, Deserialize, Principal};
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub contract_id: u64,
+    pub ledger: Principal,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerReserves {
+    pub ledger: Principal,
+    /// What open escrows on this ledger should hold in total.
+    pub locked: u64,
+    /// What their subaccounts held when checked.
+    pub held: u64,
+    /// Sum of the shortfalls of the escrows holding less than they should.
+    pub deficit: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReserveReport {
+    pub checked_at: u64,
+    pub ledgers: Vec<LedgerReserves>,
+    pub discrepancies: Vec<Discrepancy>,
+    /// Escrows whose balance could not be read, with the error.
+    pub errors: Vec<(u64, String)>,
+    /// Whether this run paused payouts.
+    pub paused_payouts: bool,
+}
+
+/// What the contract's subaccount has to hold: everything not yet paid out
+/// while the contract is open, nothing otherwise. Leftover fees and dust in
+/// settled escrows are a surplus and are not checked.
+pub fn locked(contract: &EscrowContract) -> u64 {
+    match contract.status {
+        ContractStatus::Active | ContractStatus::Disputed => contract.unpaid_amount(),
+        _ => 0,
+    }
+}
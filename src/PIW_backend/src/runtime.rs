@@ -0,0 +1,99 @@
+//! The two things the escrow logic needs from the outside world: a ledger to
+//! move funds on and a clock. On the IC they are the ICRC ledger canisters
+//! and the replica's time; unit tests swap in the mocks from `mock.rs`.
+
+use crate::error::EscrowResult;
+use crate::ledger;
+use candid::Principal;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+
+pub trait Clock {
+    /// Nanoseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+// Only ever used through generics inside the crate, so the futures not being
+// `Send` does not matter.
+#[allow(async_fn_in_trait)]
+pub trait Ledger {
+    async fn fee(&self, ledger: Principal) -> EscrowResult<u64>;
+
+    async fn decimals(&self, ledger: Principal) -> EscrowResult<u8>;
+
+    async fn balance_of(&self, ledger: Principal, account: Account) -> EscrowResult<u64>;
+
+    /// Pulls `amount` from `from` into `to` using the allowance `from` granted
+    /// the canister. Returns the block index, also when the ledger recognises
+    /// the pull as a repeat of one with the same memo and creation time.
+    async fn transfer_from(
+        &self,
+        ledger: Principal,
+        from: Account,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64>;
+
+    /// Sends `amount` out of one of the canister's subaccounts. Returns the
+    /// block index, also when the ledger recognises the transfer as a repeat
+    /// of one with the same memo and creation time.
+    async fn transfer(
+        &self,
+        ledger: Principal,
+        from_subaccount: Subaccount,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64>;
+}
+
+pub struct IcClock;
+
+impl Clock for IcClock {
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+}
+
+/// Calls the real ICRC-1/ICRC-2 ledger canisters.
+pub struct IcLedger;
+
+impl Ledger for IcLedger {
+    async fn fee(&self, ledger: Principal) -> EscrowResult<u64> {
+        ledger::fee(ledger).await
+    }
+
+    async fn decimals(&self, ledger: Principal) -> EscrowResult<u8> {
+        ledger::decimals(ledger).await
+    }
+
+    async fn balance_of(&self, ledger: Principal, account: Account) -> EscrowResult<u64> {
+        ledger::balance_of(ledger, account).await
+    }
+
+    async fn transfer_from(
+        &self,
+        ledger: Principal,
+        from: Account,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64> {
+        ledger::transfer_from(ledger, from, to, amount, memo, created_at_time).await
+    }
+
+    async fn transfer(
+        &self,
+        ledger: Principal,
+        from_subaccount: Subaccount,
+        to: Account,
+        amount: u64,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+    ) -> EscrowResult<u64> {
+        ledger::transfer(ledger, from_subaccount, to, amount, memo, created_at_time).await
+    }
+}
@@ -0,0 +1,2567 @@
+//! The escrow business logic, independent of the IC runtime. Every entry
+//! point takes the calling principal explicitly and reaches the ledger and
+//! the clock only through the `Ledger` and `Clock` traits, so it runs the same
+//! under `cargo test` with mocks as it does in the canister.
+
+use crate::amendment::{self, Amendment, AmendmentChange, TermsVersion};
+use crate::cancellation::{self, Cancellation};
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::{ContractStatus, CreateEscrowArgs, DisputeResolution, EscrowContract, EscrowEvent};
+use crate::evidence::{self, Evidence, EvidenceKind};
+use crate::idempotency::{self, Request};
+use crate::kyc;
+use crate::ledger;
+use crate::limits::{self, LimitKind};
+use crate::lock::ContractLock;
+use crate::log::{self, LogLevel};
+use crate::monitoring;
+use crate::notifications;
+use crate::pause::{self, PauseInfo, PauseScope};
+use crate::reputation::{self, Rating};
+use crate::reserves::{self, Discrepancy, LedgerReserves, ReserveReport};
+use crate::runtime::{Clock, Ledger};
+use crate::split::{self, SplitPayee, SplitTransfer};
+use crate::state::{self, LedgerConfig};
+use crate::subscription::{self, CreateSubscriptionArgs, Subscription, SubscriptionStatus};
+use crate::template::Industry;
+use crate::transfers::{self, PendingTransfer, TransferKind};
+use crate::vendor::{self, Vendor, VendorArgs, VendorStatus};
+use candid::{CandidType, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::de::DeserializeOwned;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::future::Future;
+
+pub struct Service<L, C> {
+    pub ledger: L,
+    pub clock: C,
+    /// Owner of the escrow subaccounts and of collected fees.
+    pub canister_id: Principal,
+}
+
+impl<L: Ledger, C: Clock> Service<L, C> {
+    fn log(&self, level: LogLevel, caller: Principal, contract_id: Option<u64>, message: String) {
+        let now = self.clock.now();
+        state::mutate(|s| log::append(s, level, caller, contract_id, message, now));
+    }
+
+    /// Registers a ledger, remembering its transfer fee and decimals.
+    pub async fn add_ledger(&self, ledger: Principal) -> EscrowResult<()> {
+        let fee = self.ledger.fee(ledger).await?;
+        let decimals = self.ledger.decimals(ledger).await?;
+        state::mutate(|s| s.ledgers.insert(ledger, LedgerConfig { fee, decimals: Some(decimals) }));
+        Ok(())
+    }
+
+    fn contract(&self, contract_id: u64) -> EscrowResult<EscrowContract> {
+        state::read(|s| s.escrows.get(&contract_id).cloned()).ok_or(EscrowError::NotFound)
+    }
+
+    fn ledger_fee(&self, ledger: Principal) -> EscrowResult<u64> {
+        state::read(|s| s.ledgers.get(&ledger).map(|l| l.fee)).ok_or(EscrowError::UnsupportedLedger(ledger))
+    }
+
+    /// Applies a status change and tells both parties about it.
+    fn transition(&self, contract_id: u64, status: ContractStatus, actor: Principal, event: EscrowEvent, message: String) {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let Some(contract) = s.escrows.get_mut(&contract_id) else {
+                return;
+            };
+            contract.transition(status, actor, event, now);
+            let (payer, payee) = (contract.payer, contract.payee);
+            notifications::notify(s, payer, message.clone(), Some(contract_id), now);
+            notifications::notify(s, payee, message.clone(), Some(contract_id), now);
+        });
+        self.log(LogLevel::Info, actor, Some(contract_id), message);
+    }
+
+    /// Queues `gross`, less the ledger fee, to go out of the escrow to `to`
+    /// and sends it. The contract only moves on once the ledger confirms.
+    async fn queue_payout(
+        &self,
+        contract: &EscrowContract,
+        kind: TransferKind,
+        to: Account,
+        gross: u64,
+        actor: Principal,
+        event: Option<EscrowEvent>,
+    ) -> EscrowResult<u64> {
+        let fee = self.ledger_fee(contract.ledger)?;
+        let now = self.clock.now();
+        let transfer_id = state::mutate(|s| {
+            transfers::enqueue(s, contract.id, kind, contract.ledger, to, gross.saturating_sub(fee), actor, event, now)
+        });
+        self.send_transfer(transfer_id).await
+    }
+
+    /// The payout of `kind` already queued for the contract, if any. A queued
+    /// payout of another kind has to go through or give up first.
+    fn queued_payout(&self, contract_id: u64, kind: &TransferKind) -> EscrowResult<Option<u64>> {
+        state::read(|s| {
+            let pending = transfers::pending_for(s, contract_id);
+            if pending.iter().any(|t| t.kind != *kind) {
+                return Err(EscrowError::PayoutPending);
+            }
+            Ok(pending.first().map(|t| t.id))
+        })
+    }
+
+    /// Sends a queued transfer with the memo and creation time it was queued
+    /// with. Callers hold the contract's lock. Whoever sees the confirmation
+    /// first applies it, so a repeat the ledger answers with the same block
+    /// changes nothing.
+    async fn send_transfer(&self, transfer_id: u64) -> EscrowResult<u64> {
+        let transfer = state::read(|s| s.pending_transfers.get(&transfer_id).cloned()).ok_or(EscrowError::NotFound)?;
+        let result = self.ledger.transfer(
+            transfer.ledger,
+            ledger::escrow_subaccount(transfer.contract_id),
+            transfer.to,
+            transfer.amount,
+            Some(transfers::memo(transfer_id)),
+            Some(transfer.created_at_time),
+        )
+        .await;
+        match result {
+            Ok(block_index) => {
+                if state::mutate(|s| s.pending_transfers.remove(&transfer_id)).is_some() {
+                    self.confirm_transfer(transfer, block_index).await;
+                }
+                Ok(block_index)
+            }
+            Err(e) => {
+                let now = self.clock.now();
+                if let Some(dropped) = state::mutate(|s| transfers::record_failure(s, transfer_id, &e, now)) {
+                    self.log(
+                        LogLevel::Error,
+                        dropped.actor,
+                        Some(dropped.contract_id),
+                        format!(
+                            "gave up on {:?} payout {} for contract {} after {} attempts: {:?}",
+                            dropped.kind, transfer_id, dropped.contract_id, dropped.attempts, e
+                        ),
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn confirm_transfer(&self, transfer: PendingTransfer, block_index: u64) {
+        match transfer.kind {
+            TransferKind::Release => self.finish_release(transfer.contract_id, transfer.actor, transfer.event, block_index).await,
+            TransferKind::Refund => self.finish_refund(transfer.contract_id, transfer.actor, transfer.event, block_index).await,
+            // The release itself is finished once every share is in.
+            TransferKind::SplitShare { recipient } => state::mutate(|s| {
+                let paid = s
+                    .escrows
+                    .get_mut(&transfer.contract_id)
+                    .and_then(|c| c.splits.as_mut())
+                    .and_then(|splits| splits.iter_mut().find(|p| p.recipient == recipient));
+                if let Some(paid) = paid {
+                    paid.block_index = Some(block_index);
+                }
+            }),
+            // The refund of the rest follows.
+            TransferKind::CancellationShare => state::mutate(|s| {
+                let Some(contract) = s.escrows.get_mut(&transfer.contract_id) else {
+                    return;
+                };
+                if let Some(cancellation) = contract.cancellation.as_mut() {
+                    cancellation.payee_block = Some(block_index);
+                }
+                let event = EscrowEvent::CancellationPayout {
+                    amount: transfer.amount,
+                    block_index,
+                };
+                contract.record(transfer.actor, event, self.clock.now());
+            }),
+        }
+    }
+
+    async fn finish_release(&self, contract_id: u64, actor: Principal, event: Option<EscrowEvent>, block_index: u64) {
+        if let Some(event) = event {
+            state::mutate(|s| {
+                if let Some(c) = s.escrows.get_mut(&contract_id) {
+                    c.record(actor, event, self.clock.now());
+                }
+            });
+        }
+        self.transition(
+            contract_id,
+            ContractStatus::Released,
+            actor,
+            EscrowEvent::Released { block_index },
+            format!("Funds released for contract {}", contract_id),
+        );
+        if let Ok(contract) = self.contract(contract_id) {
+            self.collect_fees(&contract).await;
+        }
+        monitoring::record_call_cost("release", self.clock.now());
+    }
+
+    async fn finish_refund(&self, contract_id: u64, actor: Principal, event: Option<EscrowEvent>, block_index: u64) {
+        if let Some(event) = event {
+            state::mutate(|s| {
+                if let Some(c) = s.escrows.get_mut(&contract_id) {
+                    c.record(actor, event, self.clock.now());
+                }
+            });
+        }
+        self.transition(
+            contract_id,
+            ContractStatus::Refunded,
+            actor,
+            EscrowEvent::Refunded { block_index: Some(block_index) },
+            format!("Funds refunded for contract {}", contract_id),
+        );
+        // A cancellation that paid the payee a share leaves the fee on it behind.
+        if let Ok(contract) = self.contract(contract_id) {
+            if contract.cancellation.as_ref().is_some_and(|c| c.payee_block.is_some()) {
+                self.collect_fees(&contract).await;
+            }
+        }
+        monitoring::record_call_cost("refund", self.clock.now());
+    }
+
+    /// Finishes a split release once the last share has been confirmed.
+    async fn finish_split_release(&self, contract_id: u64, actor: Principal, event: Option<EscrowEvent>) -> Option<u64> {
+        let contract = self.contract(contract_id).ok()?;
+        if contract.status == ContractStatus::Released {
+            return None;
+        }
+        let splits = contract.splits.as_ref()?;
+        if splits.iter().any(|s| s.block_index.is_none()) {
+            return None;
+        }
+        let block_index = splits.iter().filter_map(|s| s.block_index).max()?;
+        self.finish_release(contract_id, actor, event, block_index).await;
+        Some(block_index)
+    }
+
+    /// Moves whatever is left in a released escrow, i.e. the platform fee plus
+    /// rounding dust, to the canister's main account. Not queued: the amount is
+    /// read from the balance each time, so a repeat moves only what is left.
+    async fn collect_fees(&self, contract: &EscrowContract) {
+        if contract.fee_bps == 0 {
+            return;
+        }
+        let result = async {
+            let fee = self.ledger_fee(contract.ledger)?;
+            let account = ledger::escrow_account(self.canister_id, contract.id);
+            let balance = self.ledger.balance_of(contract.ledger, account).await?;
+            if balance <= fee {
+                return Ok(None);
+            }
+            let amount = balance - fee;
+            let block_index = self.ledger.transfer(
+                contract.ledger,
+                ledger::escrow_subaccount(contract.id),
+                Account::from(self.canister_id),
+                amount,
+                None,
+                None,
+            )
+            .await?;
+            Ok::<_, EscrowError>(Some((amount, block_index)))
+        }
+        .await;
+        match result {
+            Ok(Some((amount, block_index))) => state::mutate(|s| {
+                if let Some(c) = s.escrows.get_mut(&contract.id) {
+                    c.record(self.canister_id, EscrowEvent::FeeCollected { amount, block_index }, self.clock.now());
+                }
+            }),
+            Ok(None) => {}
+            Err(e) => self.log(
+                LogLevel::Error,
+                self.canister_id,
+                Some(contract.id),
+                format!("fee collection for contract {} failed: {:?}", contract.id, e),
+            ),
+        }
+    }
+
+
+    /// Queues every split share that is neither paid nor queued yet, sends all
+    /// queued shares and records the outcome of each transfer. Returns the last
+    /// block index if all went through.
+    async fn pay_out_splits(
+        &self,
+        contract: &EscrowContract,
+        splits: &[SplitPayee],
+        actor: Principal,
+        event: Option<EscrowEvent>,
+    ) -> EscrowResult<u64> {
+        let fee = self.ledger_fee(contract.ledger)?;
+        let now = self.clock.now();
+        let queued = state::mutate(|s| {
+            for split in splits.iter().filter(|p| p.block_index.is_none()) {
+                let kind = TransferKind::SplitShare { recipient: split.recipient };
+                if transfers::pending_for(s, contract.id).iter().any(|t| t.kind == kind) {
+                    continue;
+                }
+                let net = (split.amount - contract.fee_on(split.amount)).saturating_sub(fee);
+                let to = Account::from(split.recipient);
+                transfers::enqueue(s, contract.id, kind, contract.ledger, to, net, actor, event.clone(), now);
+            }
+            transfers::pending_for(s, contract.id)
+                .into_iter()
+                .filter_map(|t| match t.kind {
+                    TransferKind::SplitShare { recipient } => Some((t.id, recipient)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        });
+        if queued.is_empty() {
+            return Err(EscrowError::InvalidArgument("split has already been paid out".to_string()));
+        }
+        let mut transfers = Vec::new();
+        for (transfer_id, recipient) in queued {
+            let result = self.send_transfer(transfer_id).await;
+            let amount = splits.iter().find(|p| p.recipient == recipient).map_or(0, |p| p.amount);
+            transfers.push(SplitTransfer {
+                recipient,
+                amount,
+                result: result.map_err(|e| format!("{:?}", e)),
+            });
+        }
+        self.record_split_payout(contract.id, actor, transfers.clone());
+        let failed = transfers.iter().filter(|t| t.result.is_err()).count();
+        if failed > 0 {
+            return Err(EscrowError::Ledger(format!("{} of {} split transfers failed", failed, transfers.len())));
+        }
+        self.finish_split_release(contract.id, actor, event)
+            .await
+            .ok_or(EscrowError::InvalidArgument("split has already been paid out".to_string()))
+    }
+
+    fn record_split_payout(&self, contract_id: u64, actor: Principal, transfers: Vec<SplitTransfer>) {
+        state::mutate(|s| {
+            if let Some(c) = s.escrows.get_mut(&contract_id) {
+                c.record(actor, EscrowEvent::SplitPayout { transfers }, self.clock.now());
+            }
+        });
+    }
+
+    /// Pays the payee on behalf of the parties, which a release approval
+    /// policy, if any, has to allow.
+    async fn release_to_payee(&self, contract: &EscrowContract, actor: Principal, event: Option<EscrowEvent>) -> EscrowResult<u64> {
+        if !contract.release_approved() {
+            return Err(EscrowError::ApprovalRequired);
+        }
+        self.pay_payee(contract, actor, event).await
+    }
+
+    /// For split escrows the returned block index is that of the last transfer;
+    /// the per-recipient results are recorded in the contract history. Asking
+    /// again while the payout is queued sends the queued transfer again.
+    async fn pay_payee(&self, contract: &EscrowContract, actor: Principal, event: Option<EscrowEvent>) -> EscrowResult<u64> {
+        if contract.cancellation_agreed() {
+            return Err(EscrowError::CancellationAgreed);
+        }
+        pause::check(PauseScope::Releases)?;
+        if let Some(splits) = &contract.splits {
+            return self.pay_out_splits(contract, splits, actor, event).await;
+        }
+        if let Some(transfer_id) = self.queued_payout(contract.id, &TransferKind::Release)? {
+            return self.send_transfer(transfer_id).await;
+        }
+        let unpaid = contract.unpaid_amount();
+        let net = unpaid - contract.fee_on(unpaid);
+        self.queue_payout(contract, TransferKind::Release, contract.payee_account(), net, actor, event)
+            .await
+    }
+
+    async fn refund_to_payer(&self, contract: &EscrowContract, actor: Principal, event: Option<EscrowEvent>) -> EscrowResult<u64> {
+        if contract.cancellation_agreed() {
+            return Err(EscrowError::CancellationAgreed);
+        }
+        self.refund_unpaid(contract, actor, event).await
+    }
+
+    async fn refund_unpaid(&self, contract: &EscrowContract, actor: Principal, event: Option<EscrowEvent>) -> EscrowResult<u64> {
+        pause::check(PauseScope::Refunds)?;
+        if let Some(transfer_id) = self.queued_payout(contract.id, &TransferKind::Refund)? {
+            return self.send_transfer(transfer_id).await;
+        }
+        let to = Account::from(contract.payer);
+        self.queue_payout(contract, TransferKind::Refund, to, contract.unpaid_amount(), actor, event)
+            .await
+    }
+
+    /// Timer entry point: sends queued payouts whose next attempt is due,
+    /// unless that kind of payout is paused.
+    pub async fn retry_pending_transfers(&self) {
+        let due = state::read(|s| transfers::due(s, self.clock.now()));
+        for transfer in due {
+            let scope = match transfer.kind {
+                TransferKind::Refund => PauseScope::Refunds,
+                TransferKind::Release | TransferKind::SplitShare { .. } | TransferKind::CancellationShare => {
+                    PauseScope::Releases
+                }
+            };
+            if pause::check(scope).is_err() {
+                continue;
+            }
+            let Ok(_lock) = ContractLock::acquire(transfer.contract_id) else {
+                continue;
+            };
+            let result = self.send_transfer(transfer.id).await;
+            if let TransferKind::SplitShare { recipient } = transfer.kind {
+                let amount = self
+                    .contract(transfer.contract_id)
+                    .ok()
+                    .and_then(|c| c.splits)
+                    .and_then(|splits| splits.into_iter().find(|p| p.recipient == recipient))
+                    .map_or(0, |p| p.amount);
+                let split = SplitTransfer {
+                    recipient,
+                    amount,
+                    result: result.as_ref().copied().map_err(|e| format!("{:?}", e)),
+                };
+                self.record_split_payout(transfer.contract_id, self.canister_id, vec![split]);
+                if result.is_ok() {
+                    self.finish_split_release(transfer.contract_id, transfer.actor, transfer.event).await;
+                }
+            }
+            if transfer.kind == TransferKind::CancellationShare && result.is_ok() {
+                // A failure here is queued and retried like any other refund.
+                let _ = self.pay_out_cancellation(transfer.contract_id, transfer.actor).await;
+            }
+        }
+    }
+
+    /// Pays the payee's agreed share, if any and not paid yet, then returns
+    /// the rest to the payer.
+    async fn pay_out_cancellation(&self, contract_id: u64, actor: Principal) -> EscrowResult<u64> {
+        let contract = self.contract(contract_id)?;
+        let cancellation = contract.cancellation.as_ref().ok_or(EscrowError::NotFound)?;
+        if cancellation.payee_amount > 0 && cancellation.payee_block.is_none() {
+            pause::check(PauseScope::Releases)?;
+            match self.queued_payout(contract_id, &TransferKind::CancellationShare)? {
+                Some(transfer_id) => self.send_transfer(transfer_id).await?,
+                None => {
+                    let net = cancellation.payee_amount - contract.fee_on(cancellation.payee_amount);
+                    self.queue_payout(&contract, TransferKind::CancellationShare, contract.payee_account(), net, actor, None)
+                        .await?
+                }
+            };
+        }
+        let contract = self.contract(contract_id)?;
+        self.refund_unpaid(&contract, actor, None).await
+    }
+
+    /// Runs `call` unless the caller already made `request` under `key`, in
+    /// which case the result of that first call is returned again.
+    pub fn idempotent<T: CandidType + DeserializeOwned>(
+        &self,
+        caller: Principal,
+        key: Option<String>,
+        request: Request,
+        call: impl FnOnce() -> EscrowResult<T>,
+    ) -> EscrowResult<T> {
+        let Some(key) = key else {
+            return call();
+        };
+        idempotency::validate(&key)?;
+        if let Some(result) = state::read(|s| idempotency::replay(s, caller, &key, &request, self.clock.now()))? {
+            return Ok(result);
+        }
+        let result = call()?;
+        state::mutate(|s| idempotency::record(s, caller, key, request, &result, self.clock.now()));
+        Ok(result)
+    }
+
+    /// `idempotent` for calls that await. A replay while the first call is
+    /// still running is not caught here; the contract lock turns it away.
+    pub async fn idempotent_async<T: CandidType + DeserializeOwned>(
+        &self,
+        caller: Principal,
+        key: Option<String>,
+        request: Request,
+        call: impl Future<Output = EscrowResult<T>>,
+    ) -> EscrowResult<T> {
+        let Some(key) = key else {
+            return call.await;
+        };
+        idempotency::validate(&key)?;
+        if let Some(result) = state::read(|s| idempotency::replay(s, caller, &key, &request, self.clock.now()))? {
+            return Ok(result);
+        }
+        let result = call.await?;
+        state::mutate(|s| idempotency::record(s, caller, key, request, &result, self.clock.now()));
+        Ok(result)
+    }
+
+    /// Checks shared by every way of opening an escrow. Returns the ledger fee.
+    fn validate_terms(&self, payer: Principal, payee: Principal, ledger: Principal, amount: u64) -> EscrowResult<u64> {
+        pause::check(PauseScope::Creation)?;
+        if payer == Principal::anonymous() {
+            return Err(EscrowError::Unauthorized);
+        }
+        state::read(|s| limits::check_creation(s, payer, self.clock.now()))?;
+        state::read(|s| kyc::check(s, payer, payee, ledger, amount, self.clock.now()))?;
+        if payee == payer {
+            return Err(EscrowError::InvalidArgument("payer and payee must differ".to_string()));
+        }
+        let fee = self.ledger_fee(ledger)?;
+        if amount <= fee {
+            return Err(EscrowError::InvalidArgument(format!("amount must exceed the ledger fee of {}", fee)));
+        }
+        Ok(fee)
+    }
+
+    pub fn create_escrow(&self, caller: Principal, args: CreateEscrowArgs) -> EscrowResult<u64> {
+        let fee_bps = state::read(|s| s.platform_fee_bps);
+        self.open_escrow(caller, args, None, fee_bps)
+    }
+
+    /// Like `create_escrow`, but any term left empty is taken from the owner's
+    /// template for `industry`, and the template's fee override applies.
+    pub fn create_escrow_from_template(&self, caller: Principal, industry: Industry, mut args: CreateEscrowArgs) -> EscrowResult<u64> {
+        let (template, platform_fee_bps) = state::read(|s| (s.templates.get(&industry).cloned(), s.platform_fee_bps));
+        let template = template.ok_or(EscrowError::TemplateNotFound(industry))?;
+        template.apply(&mut args, self.clock.now());
+        self.open_escrow(caller, args, Some(industry), template.fee_bps.unwrap_or(platform_fee_bps))
+    }
+
+    fn open_escrow(&self, payer: Principal, args: CreateEscrowArgs, industry: Option<Industry>, fee_bps: u32) -> EscrowResult<u64> {
+        let now = self.clock.now();
+        let fee = self.validate_terms(payer, args.payee, args.ledger, args.amount)?;
+        let platform_fee = (args.amount as u128 * fee_bps as u128 / 10_000) as u64;
+        if args.amount - platform_fee <= fee {
+            return Err(EscrowError::InvalidArgument(format!(
+                "amount after the platform fee must exceed the ledger fee of {}",
+                fee
+            )));
+        }
+        if let Some(lock) = &args.hash_lock {
+            lock.validate(now)?;
+        }
+        args.validate_delivery_terms(now)?;
+        if let Some(policy) = &args.release_policy {
+            policy.validate()?;
+            // Both would let the funds go without the approvers.
+            if args.hash_lock.is_some() || args.inspection_window_secs.is_some() {
+                return Err(EscrowError::InvalidArgument(
+                    "a release policy cannot be combined with a hash lock or inspection window".to_string(),
+                ));
+            }
+        }
+        let payee_account = match &args.vendor_id {
+            Some(handle) => state::read(|s| vendor::payout_for(s, handle, args.payee, args.ledger))?,
+            None => None,
+        };
+        let splits = args
+            .splits
+            .as_ref()
+            .map(|shares| split::allocate(args.amount, shares, fee))
+            .transpose()?;
+
+        state::mutate(|s| {
+            let id = s.allocate_escrow_id();
+            let mut contract = EscrowContract::new(id, payer, args, splits, now)?;
+            limits::record_creation(s, payer, now);
+            contract.industry = industry;
+            contract.fee_bps = fee_bps;
+            contract.payee_account = payee_account;
+            notifications::notify(
+                s,
+                contract.payer,
+                format!("Escrow contract created. Payee: {}, Amount: {}", contract.payee, contract.amount),
+                Some(id),
+                now,
+            );
+            notifications::notify(
+                s,
+                contract.payee,
+                format!("You have been added as the payee for an escrow contract. Amount: {}", contract.amount),
+                Some(id),
+                now,
+            );
+            s.escrows.insert(id, contract);
+            Ok(id)
+        })
+    }
+
+    pub fn accept_escrow(&self, caller: Principal, contract_id: u64) -> EscrowResult<()> {
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payee {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Pending)?;
+        self.transition(
+            contract_id,
+            ContractStatus::Accepted,
+            caller,
+            EscrowEvent::Accepted,
+            format!("Contract {} accepted by the payee", contract_id),
+        );
+        Ok(())
+    }
+
+    /// Moves the escrow amount from the payer into the contract's subaccount. The
+    /// payer must have approved this canister for `amount` plus the ledger fee.
+    pub async fn fund_escrow(&self, caller: Principal, contract_id: u64) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payer {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Accepted)?;
+        pause::check(PauseScope::Funding)?;
+        // Attestations may have lapsed since the contract was created.
+        state::read(|s| kyc::check(s, contract.payer, contract.payee, contract.ledger, contract.amount, self.clock.now()))?;
+        let block_index = self.ledger.transfer_from(
+            contract.ledger,
+            Account::from(contract.payer),
+            ledger::escrow_account(self.canister_id, contract_id),
+            contract.amount,
+            None,
+            None,
+        )
+        .await?;
+        self.transition(
+            contract_id,
+            ContractStatus::Active,
+            caller,
+            EscrowEvent::Funded { block_index },
+            format!("Contract {} funded", contract_id),
+        );
+        monitoring::record_call_cost("fund_escrow", self.clock.now());
+        Ok(block_index)
+    }
+
+    pub async fn release_funds(&self, caller: Principal, contract_id: u64) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payer {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        if contract.release_approvals.is_some() {
+            return Err(EscrowError::ApprovalRequired);
+        }
+        self.release_to_payee(&contract, caller, None).await
+    }
+
+    /// Records the caller's approval to release a multi-signature escrow. The
+    /// approval that meets the threshold also performs the payout, in which case
+    /// the block index is returned.
+    pub async fn approve_release(&self, approver: Principal, contract_id: u64) -> EscrowResult<Option<u64>> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let now = self.clock.now();
+        let contract = state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            contract.require_status(ContractStatus::Active)?;
+            let approvals = contract.release_approvals.as_mut().ok_or(EscrowError::InvalidArgument(
+                "contract has no release approval policy".to_string(),
+            ))?;
+            let already_met = approvals.is_met();
+            approvals.approve(approver)?;
+            if !already_met {
+                contract.record(approver, EscrowEvent::ReleaseApproved, now);
+            }
+            Ok(contract.clone())
+        })?;
+        if contract.release_approvals.as_ref().is_some_and(|a| a.is_met()) {
+            self.release_to_payee(&contract, approver, None).await.map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Withdraws an approval given earlier. Only possible while the threshold
+    /// has not been reached, since reaching it releases the funds.
+    pub fn revoke_release_approval(&self, approver: Principal, contract_id: u64) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            contract.require_status(ContractStatus::Active)?;
+            let approvals = contract.release_approvals.as_mut().ok_or(EscrowError::InvalidArgument(
+                "contract has no release approval policy".to_string(),
+            ))?;
+            approvals.revoke(approver)?;
+            contract.record(approver, EscrowEvent::ReleaseApprovalRevoked, now);
+            Ok(())
+        })
+    }
+
+    /// Before funding the payer may simply call the deal off. Once funded only
+    /// the payee can hand the money back.
+    pub async fn refund_funds(&self, caller: Principal, contract_id: u64) -> EscrowResult<Option<u64>> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        match contract.status {
+            ContractStatus::Pending | ContractStatus::Accepted if caller == contract.payer => {
+                self.transition(
+                    contract_id,
+                    ContractStatus::Refunded,
+                    caller,
+                    EscrowEvent::Refunded { block_index: None },
+                    format!("Contract {} cancelled by the payer", contract_id),
+                );
+                Ok(None)
+            }
+            ContractStatus::Active if caller == contract.payee => {
+                self.refund_to_payer(&contract, caller, None).await.map(Some)
+            }
+            ContractStatus::Pending | ContractStatus::Accepted | ContractStatus::Active => Err(EscrowError::Unauthorized),
+            status => Err(EscrowError::InvalidStatus(status)),
+        }
+    }
+
+    /// Proposes to call off an active escrow, with `payee_amount` going to the
+    /// payee and the rest back to the payer. Either party may propose, and a
+    /// new proposal replaces the previous one, so the other party can answer
+    /// with a counter-offer.
+    pub fn propose_cancellation(&self, caller: Principal, contract_id: u64, payee_amount: u64, reason: String) -> EscrowResult<()> {
+        let now = self.clock.now();
+        let ledger = self.contract(contract_id)?.ledger;
+        let fee = self.ledger_fee(ledger)?;
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            if !contract.is_party(caller) {
+                return Err(EscrowError::Unauthorized);
+            }
+            contract.require_status(ContractStatus::Active)?;
+            if contract.cancellation_agreed() {
+                return Err(EscrowError::CancellationAgreed);
+            }
+            cancellation::validate(contract, payee_amount, &reason, fee)?;
+            contract.cancellation = Some(Cancellation {
+                proposed_by: caller,
+                payee_amount,
+                reason: reason.clone(),
+                proposed_at: now,
+                confirmed_at: None,
+                payee_block: None,
+            });
+            contract.record(caller, EscrowEvent::CancellationProposed { payee_amount, reason }, now);
+            let other = if caller == contract.payer { contract.payee } else { contract.payer };
+            let message = format!("Cancellation of contract {} proposed", contract_id);
+            notifications::notify(s, other, message, Some(contract_id), now);
+            Ok(())
+        })
+    }
+
+    /// Takes back the caller's own proposal as long as it is not confirmed.
+    pub fn withdraw_cancellation(&self, caller: Principal, contract_id: u64) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            let cancellation = contract.cancellation.as_ref().ok_or(EscrowError::NotFound)?;
+            if cancellation.proposed_by != caller {
+                return Err(EscrowError::Unauthorized);
+            }
+            if cancellation.is_agreed() {
+                return Err(EscrowError::CancellationAgreed);
+            }
+            contract.cancellation = None;
+            contract.record(caller, EscrowEvent::CancellationWithdrawn, now);
+            Ok(())
+        })
+    }
+
+    /// The other party accepts the proposal and the escrow is paid out as
+    /// proposed; `payee_amount` must repeat the proposed amount, so a proposal
+    /// replaced in the meantime is not confirmed by accident. Once confirmed,
+    /// either party can call this again to retry payouts that failed. Returns
+    /// the block index of the refund to the payer.
+    pub async fn confirm_cancellation(&self, caller: Principal, contract_id: u64, payee_amount: u64) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        if !contract.is_party(caller) {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        let cancellation = contract.cancellation.as_ref().ok_or(EscrowError::NotFound)?;
+        if payee_amount != cancellation.payee_amount {
+            return Err(EscrowError::InvalidArgument(
+                "payee_amount does not match the proposed cancellation".to_string(),
+            ));
+        }
+        if !cancellation.is_agreed() {
+            if cancellation.proposed_by == caller {
+                return Err(EscrowError::Unauthorized);
+            }
+            // The ledger fee may have changed since the proposal.
+            cancellation::validate(&contract, payee_amount, &cancellation.reason, self.ledger_fee(contract.ledger)?)?;
+            // A payout already under way has to finish or give up first.
+            if state::read(|s| !transfers::pending_for(s, contract_id).is_empty()) {
+                return Err(EscrowError::PayoutPending);
+            }
+            pause::check(PauseScope::Refunds)?;
+            if payee_amount > 0 {
+                pause::check(PauseScope::Releases)?;
+            }
+            let now = self.clock.now();
+            state::mutate(|s| {
+                let Some(contract) = s.escrows.get_mut(&contract_id) else {
+                    return;
+                };
+                if let Some(cancellation) = contract.cancellation.as_mut() {
+                    cancellation.confirmed_at = Some(now);
+                }
+                contract.record(caller, EscrowEvent::CancellationConfirmed { payee_amount }, now);
+            });
+            self.log(
+                LogLevel::Info,
+                caller,
+                Some(contract_id),
+                format!("Cancellation of contract {} confirmed", contract_id),
+            );
+        }
+        self.pay_out_cancellation(contract_id, caller).await
+    }
+
+    /// Proposes a change to the terms of an active escrow. A new proposal from
+    /// either party replaces the previous one.
+    pub fn propose_amendment(&self, caller: Principal, contract_id: u64, change: AmendmentChange) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            if !contract.is_party(caller) {
+                return Err(EscrowError::Unauthorized);
+            }
+            contract.require_status(ContractStatus::Active)?;
+            if contract.cancellation_agreed() {
+                return Err(EscrowError::CancellationAgreed);
+            }
+            amendment::validate(contract, &change, now)?;
+            contract.amendment = Some(Amendment {
+                proposed_by: caller,
+                change: change.clone(),
+                proposed_at: now,
+            });
+            contract.record(caller, EscrowEvent::AmendmentProposed { change }, now);
+            let other = if caller == contract.payer { contract.payee } else { contract.payer };
+            let message = format!("Amendment of contract {} proposed", contract_id);
+            notifications::notify(s, other, message, Some(contract_id), now);
+            Ok(())
+        })
+    }
+
+    pub fn withdraw_amendment(&self, caller: Principal, contract_id: u64) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            let amendment = contract.amendment.as_ref().ok_or(EscrowError::NotFound)?;
+            if amendment.proposed_by != caller {
+                return Err(EscrowError::Unauthorized);
+            }
+            contract.amendment = None;
+            contract.record(caller, EscrowEvent::AmendmentWithdrawn, now);
+            Ok(())
+        })
+    }
+
+    /// The other party approves the proposed amendment, which takes effect at
+    /// once. `change` must repeat the proposed change, so a proposal replaced
+    /// in the meantime is not approved by accident. A top-up is paid in from
+    /// the payer's allowance first and nothing changes if that fails.
+    pub async fn approve_amendment(&self, caller: Principal, contract_id: u64, change: AmendmentChange) -> EscrowResult<TermsVersion> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let mut contract = self.contract(contract_id)?;
+        if !contract.is_party(caller) {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        if contract.cancellation_agreed() {
+            return Err(EscrowError::CancellationAgreed);
+        }
+        let proposed = contract.amendment.as_ref().ok_or(EscrowError::NotFound)?;
+        if proposed.proposed_by == caller {
+            return Err(EscrowError::Unauthorized);
+        }
+        if proposed.change != change {
+            return Err(EscrowError::InvalidArgument(
+                "change does not match the proposed amendment".to_string(),
+            ));
+        }
+        // A deadline or due date may have passed since the proposal.
+        amendment::validate(&contract, &change, self.clock.now())?;
+        let block_index = match change {
+            AmendmentChange::TopUp { amount } => {
+                pause::check(PauseScope::Funding)?;
+                if state::read(|s| !transfers::pending_for(s, contract_id).is_empty()) {
+                    return Err(EscrowError::PayoutPending);
+                }
+                let total = contract.amount + amount;
+                state::read(|s| kyc::check(s, contract.payer, contract.payee, contract.ledger, total, self.clock.now()))?;
+                // Records the new terms on the copy first, so they cannot fail
+                // to hash once the funds are in.
+                amendment::apply(&mut contract, change.clone(), self.clock.now())?;
+                let block_index = self.ledger.transfer_from(
+                    contract.ledger,
+                    Account::from(contract.payer),
+                    ledger::escrow_account(self.canister_id, contract_id),
+                    amount,
+                    None,
+                    None,
+                )
+                .await?;
+                Some(block_index)
+            }
+            AmendmentChange::SetDeadline { .. } | AmendmentChange::AddMilestone(_) => None,
+        };
+        let now = self.clock.now();
+        let version = state::mutate(|s| {
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            let version = amendment::apply(contract, change, now)?;
+            contract.amendment = None;
+            let event = EscrowEvent::AmendmentApproved {
+                version: version.version,
+                hash: version.hash.clone(),
+                block_index,
+            };
+            contract.record(caller, event, now);
+            let (payer, payee) = (contract.payer, contract.payee);
+            let message = format!("Contract {} amended to terms version {}", contract_id, version.version);
+            notifications::notify(s, payer, message.clone(), Some(contract_id), now);
+            notifications::notify(s, payee, message, Some(contract_id), now);
+            Ok(version)
+        })?;
+        self.log(
+            LogLevel::Info,
+            caller,
+            Some(contract_id),
+            format!("Contract {} amended to terms version {}", contract_id, version.version),
+        );
+        Ok(version)
+    }
+
+    /// Attaches evidence to an active or disputed contract, e.g. a shipping
+    /// receipt before delivery or documents backing a dispute.
+    pub fn submit_evidence(&self, submitter: Principal, contract_id: u64, kind: EvidenceKind, description: String, data: ByteBuf) -> EscrowResult<()> {
+        let now = self.clock.now();
+        evidence::validate(&data)?;
+        state::mutate(|s| {
+            let max_bytes = limits::effective(s, submitter).max_evidence_bytes_per_dispute;
+            let contract = s.escrows.get(&contract_id).ok_or(EscrowError::NotFound)?;
+            if !contract.is_party(submitter) {
+                return Err(EscrowError::Unauthorized);
+            }
+            if !matches!(contract.status, ContractStatus::Active | ContractStatus::Disputed) {
+                return Err(EscrowError::InvalidStatus(contract.status));
+            }
+            evidence::check_capacity(s, contract, (data.len() + description.len()) as u64)?;
+            let contract = s.escrows.get_mut(&contract_id).ok_or(EscrowError::NotFound)?;
+            let submitted: u64 = contract
+                .evidence
+                .iter()
+                .filter(|e| e.submitted_by == submitter)
+                .map(|e| e.data.len() as u64)
+                .sum();
+            if submitted + data.len() as u64 > max_bytes {
+                return Err(EscrowError::LimitExceeded(LimitKind::EvidenceBytes));
+            }
+            contract.evidence.push(Evidence {
+                submitted_by: submitter,
+                kind,
+                description,
+                data,
+                submitted_at: now,
+            });
+            contract.record(submitter, EscrowEvent::EvidenceSubmitted { kind }, now);
+            Ok(())
+        })
+    }
+
+    /// The payee declares the goods or work delivered, which starts the payer's
+    /// inspection window. Any evidence the contract requires must be in first.
+    pub fn mark_delivered(&self, caller: Principal, contract_id: u64) -> EscrowResult<()> {
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payee {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        if contract.delivered_at.is_some() {
+            return Err(EscrowError::InvalidArgument("contract is already marked delivered".to_string()));
+        }
+        let missing = evidence::missing(&contract.required_evidence, &contract.evidence, contract.payee);
+        if !missing.is_empty() {
+            return Err(EscrowError::EvidenceMissing(missing));
+        }
+        let now = self.clock.now();
+        state::mutate(|s| {
+            if let Some(c) = s.escrows.get_mut(&contract_id) {
+                c.delivered_at = Some(now);
+            }
+        });
+        self.transition(
+            contract_id,
+            ContractStatus::Active,
+            caller,
+            EscrowEvent::Delivered,
+            format!("Contract {} marked as delivered", contract_id),
+        );
+        Ok(())
+    }
+
+    /// Lets the payee collect once the inspection window has passed without the
+    /// payer releasing or raising a dispute.
+    pub async fn release_after_inspection(&self, caller: Principal, contract_id: u64) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payee {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        if !contract.inspection_elapsed(self.clock.now()) {
+            return Err(EscrowError::InvalidArgument("inspection window has not elapsed".to_string()));
+        }
+        self.release_to_payee(&contract, caller, None).await
+    }
+
+    pub fn dispute_contract(&self, caller: Principal, contract_id: u64, reason: String) -> EscrowResult<()> {
+        let contract = self.contract(contract_id)?;
+        if !contract.is_party(caller) {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        if contract.cancellation_agreed() {
+            return Err(EscrowError::CancellationAgreed);
+        }
+        if state::read(|s| !transfers::pending_for(s, contract_id).is_empty()) {
+            return Err(EscrowError::PayoutPending);
+        }
+        self.transition(
+            contract_id,
+            ContractStatus::Disputed,
+            caller,
+            EscrowEvent::Disputed { reason },
+            format!("Dispute raised for contract {}", contract_id),
+        );
+        Ok(())
+    }
+
+    pub async fn resolve_dispute(&self, caller: Principal, contract_id: u64, resolution: DisputeResolution) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        contract.require_status(ContractStatus::Disputed)?;
+        let event = Some(EscrowEvent::DisputeResolved { resolution: resolution.clone() });
+        match resolution {
+            // The arbiter's ruling stands in for any release approvals.
+            DisputeResolution::ReleaseToPayee => self.pay_payee(&contract, caller, event).await,
+            DisputeResolution::RefundToPayer => self.refund_to_payer(&contract, caller, event).await,
+        }
+    }
+
+    /// Releases a hash-locked escrow to the payee. Anyone holding the secret may
+    /// call this, so a courier can confirm delivery on the payee's behalf. A
+    /// release that failed after the secret was revealed in time can be asked
+    /// for again after expiry.
+    pub async fn confirm_delivery(&self, caller: Principal, contract_id: u64, preimage: ByteBuf) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        contract.require_status(ContractStatus::Active)?;
+        let lock = contract.hash_lock.as_ref().ok_or(EscrowError::HashLockMissing)?;
+        if !lock.is_revealed() && lock.is_expired(self.clock.now()) {
+            return Err(EscrowError::HashLockExpired);
+        }
+        if !lock.matches(&preimage) {
+            return Err(EscrowError::PreimageMismatch);
+        }
+        state::mutate(|s| {
+            if let Some(lock) = s.escrows.get_mut(&contract_id).and_then(|c| c.hash_lock.as_mut()) {
+                lock.preimage = Some(preimage);
+            }
+        });
+        self.release_to_payee(&contract, caller, Some(EscrowEvent::DeliveryConfirmed)).await
+    }
+
+    /// Returns a hash-locked escrow to the payer once its lock has expired
+    /// without the preimage being revealed.
+    pub async fn reclaim_expired_escrow(&self, caller: Principal, contract_id: u64) -> EscrowResult<u64> {
+        let _lock = ContractLock::acquire(contract_id)?;
+        let contract = self.contract(contract_id)?;
+        if caller != contract.payer {
+            return Err(EscrowError::Unauthorized);
+        }
+        contract.require_status(ContractStatus::Active)?;
+        let lock = contract.hash_lock.as_ref().ok_or(EscrowError::HashLockMissing)?;
+        if !lock.is_expired(self.clock.now()) {
+            return Err(EscrowError::HashLockNotExpired);
+        }
+        if lock.is_revealed() {
+            return Err(EscrowError::PreimageRevealed);
+        }
+        self.refund_to_payer(&contract, caller, Some(EscrowEvent::HashLockExpired)).await
+    }
+
+    /// Timer entry point for hash-locked escrows. Those whose preimage was
+    /// revealed are owed to the payee once any release approvals are in, so
+    /// a release that did not go through is sent again; the others are
+    /// refunded once expired. Contracts with a
+    /// queued payout are left to the transfer retries, agreed cancellations
+    /// to their own payout, and paused payouts to after the pause.
+    pub async fn refund_expired_hash_locks(&self) {
+        let now = self.clock.now();
+        let due: Vec<(EscrowContract, bool)> = state::read(|s| {
+            s.escrows
+                .values()
+                .filter(|c| c.status == ContractStatus::Active && !c.cancellation_agreed())
+                .filter(|c| transfers::pending_for(s, c.id).is_empty())
+                .filter_map(|c| {
+                    let lock = c.hash_lock.as_ref()?;
+                    if lock.is_revealed() {
+                        c.release_approved().then(|| (c.clone(), true))
+                    } else {
+                        lock.is_expired(now).then(|| (c.clone(), false))
+                    }
+                })
+                .collect()
+        });
+        for (contract, revealed) in due {
+            let scope = if revealed { PauseScope::Releases } else { PauseScope::Refunds };
+            if pause::check(scope).is_err() {
+                continue;
+            }
+            // Busy means a payout of the contract is already under way.
+            let Ok(_lock) = ContractLock::acquire(contract.id) else {
+                continue;
+            };
+            let result = if revealed {
+                self.release_to_payee(&contract, self.canister_id, Some(EscrowEvent::DeliveryConfirmed)).await
+            } else {
+                self.refund_to_payer(&contract, self.canister_id, Some(EscrowEvent::HashLockExpired)).await
+            };
+            if let Err(e) = result {
+                let action = if revealed { "release of delivered" } else { "refund of expired" };
+                self.log(
+                    LogLevel::Error,
+                    self.canister_id,
+                    Some(contract.id),
+                    format!("{} contract {} failed: {:?}", action, contract.id, e),
+                );
+            }
+        }
+    }
+
+    /// Compares what every open escrow should hold with its balance on the
+    /// ledger, stores the report and pauses payouts if a ledger's deficit is
+    /// above its threshold. A balance read while a payout is in flight can
+    /// look short, so shortfalls are read a second time before they count.
+    pub async fn reconcile_reserves(&self) -> ReserveReport {
+        let open: Vec<(u64, Principal)> = state::read(|s| {
+            s.escrows
+                .values()
+                .filter(|c| reserves::locked(c) > 0)
+                // Whether a queued payout has left yet is unknown until the
+                // ledger confirms it.
+                .filter(|c| transfers::pending_for(s, c.id).is_empty())
+                .map(|c| (c.id, c.ledger))
+                .collect()
+        });
+        let mut totals: BTreeMap<Principal, LedgerReserves> = BTreeMap::new();
+        let mut discrepancies = Vec::new();
+        let mut errors = Vec::new();
+        for (contract_id, ledger) in open {
+            let mut result = self.read_reserve(contract_id, ledger).await;
+            if matches!(result, Ok((expected, actual)) if actual < expected) {
+                result = self.read_reserve(contract_id, ledger).await;
+            }
+            let (expected, actual) = match result {
+                Ok(amounts) => amounts,
+                Err(e) => {
+                    errors.push((contract_id, format!("{:?}", e)));
+                    continue;
+                }
+            };
+            let total = totals.entry(ledger).or_insert_with(|| LedgerReserves {
+                ledger,
+                locked: 0,
+                held: 0,
+                deficit: 0,
+            });
+            total.locked += expected;
+            total.held += actual;
+            if actual < expected {
+                total.deficit += expected - actual;
+                discrepancies.push(Discrepancy {
+                    contract_id,
+                    ledger,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        let now = self.clock.now();
+        let report = state::mutate(|s| {
+            let over: Vec<&LedgerReserves> = totals
+                .values()
+                .filter(|t| t.deficit > s.reserve_deficit_thresholds.get(&t.ledger).copied().unwrap_or(0))
+                .collect();
+            let paused_payouts = !over.is_empty();
+            if paused_payouts {
+                let reason = over
+                    .iter()
+                    .map(|t| format!("ledger {} is short {}", t.ledger, t.deficit))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                let message = format!("Payouts paused by reserve reconciliation: {}", reason);
+                for scope in [PauseScope::Releases, PauseScope::Refunds] {
+                    s.paused.entry(scope).or_insert_with(|| PauseInfo {
+                        scope,
+                        reason: reason.clone(),
+                        paused_by: self.canister_id,
+                        paused_at: now,
+                    });
+                }
+                for admin in monitoring::admins(s) {
+                    notifications::notify(s, admin, message.clone(), None, now);
+                }
+                log::append(s, LogLevel::Error, self.canister_id, None, message, now);
+            } else if !discrepancies.is_empty() {
+                let message = format!("Reserve reconciliation found {} short escrows", discrepancies.len());
+                log::append(s, LogLevel::Warn, self.canister_id, None, message, now);
+            }
+            let report = ReserveReport {
+                checked_at: now,
+                ledgers: totals.into_values().collect(),
+                discrepancies,
+                errors,
+                paused_payouts,
+            };
+            s.reserve_report = Some(report.clone());
+            report
+        });
+        monitoring::record_call_cost("reconcile_reserves", now);
+        report
+    }
+
+    /// The escrow's balance, and what the contract expects it to hold as of
+    /// after the balance came back.
+    async fn read_reserve(&self, contract_id: u64, ledger: Principal) -> EscrowResult<(u64, u64)> {
+        let actual = self
+            .ledger
+            .balance_of(ledger, ledger::escrow_account(self.canister_id, contract_id))
+            .await?;
+        let expected = state::read(|s| s.escrows.get(&contract_id).map(reserves::locked).unwrap_or(0));
+        Ok((expected, actual))
+    }
+
+    /// Lets each party of a funded contract that was released or refunded rate
+    /// the other once.
+    pub fn rate_counterparty(&self, rater: Principal, contract_id: u64, stars: u8, review: String) -> EscrowResult<()> {
+        reputation::validate(stars, &review)?;
+        let contract = self.contract(contract_id)?;
+        let ratee = if rater == contract.payer {
+            contract.payee
+        } else if rater == contract.payee {
+            contract.payer
+        } else {
+            return Err(EscrowError::Unauthorized);
+        };
+        if !matches!(contract.status, ContractStatus::Released | ContractStatus::Refunded) {
+            return Err(EscrowError::InvalidStatus(contract.status));
+        }
+        // A deal called off before any money moved says nothing about either
+        // party, and rating it would let anyone rate anyone.
+        if !contract.was_funded() {
+            return Err(EscrowError::InvalidArgument("only funded contracts can be rated".to_string()));
+        }
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let ratings = s.ratings.entry(contract_id).or_default();
+            if ratings.iter().any(|r| r.rater == rater) {
+                return Err(EscrowError::InvalidArgument("contract is already rated".to_string()));
+            }
+            ratings.push(Rating {
+                contract_id,
+                rater,
+                ratee,
+                stars,
+                review,
+                created_at: now,
+            });
+            notifications::notify(s, ratee, format!("You were rated {} stars for contract {}", stars, contract_id), Some(contract_id), now);
+            Ok(())
+        })
+    }
+
+    /// Lists `caller` in the vendor directory under `args.handle`. Each
+    /// principal may hold one profile.
+    pub fn register_vendor(&self, caller: Principal, args: VendorArgs) -> EscrowResult<()> {
+        if caller == Principal::anonymous() {
+            return Err(EscrowError::Unauthorized);
+        }
+        args.validate(self.canister_id)?;
+        let now = self.clock.now();
+        state::mutate(|s| {
+            if vendor::by_principal(s, caller).is_some() {
+                return Err(EscrowError::InvalidArgument("caller already has a vendor profile".to_string()));
+            }
+            if s.vendors.contains_key(&args.handle) {
+                return Err(EscrowError::InvalidArgument(format!("handle {} is taken", args.handle)));
+            }
+            s.vendors.insert(
+                args.handle.clone(),
+                Vendor {
+                    handle: args.handle,
+                    principal: caller,
+                    business_name: args.business_name,
+                    payout_accounts: args.payout_accounts,
+                    categories: args.categories,
+                    verified: false,
+                    status: VendorStatus::Active,
+                    created_at: now,
+                    updated_at: now,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    /// Handles are permanent. Changing the business name or payout accounts
+    /// drops the verification badge until an admin checks the profile again;
+    /// contracts already open keep the payout account they were created with.
+    pub fn update_vendor(&self, caller: Principal, args: VendorArgs) -> EscrowResult<()> {
+        args.validate(self.canister_id)?;
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let vendor = s.vendors.get_mut(&args.handle).ok_or_else(|| EscrowError::VendorNotFound(args.handle.clone()))?;
+            if vendor.principal != caller {
+                return Err(EscrowError::Unauthorized);
+            }
+            if vendor.business_name != args.business_name || vendor.payout_accounts != args.payout_accounts {
+                vendor.verified = false;
+            }
+            vendor.business_name = args.business_name;
+            vendor.payout_accounts = args.payout_accounts;
+            vendor.categories = args.categories;
+            vendor.updated_at = now;
+            Ok(())
+        })
+    }
+
+    /// Lets a vendor stop taking new escrows for a while, or start again.
+    pub fn set_vendor_active(&self, caller: Principal, active: bool) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let handle = vendor::by_principal(s, caller).map(|v| v.handle.clone()).ok_or(EscrowError::NotFound)?;
+            let vendor = s.vendors.get_mut(&handle).ok_or(EscrowError::NotFound)?;
+            if vendor.status == VendorStatus::Suspended {
+                return Err(EscrowError::VendorInactive(handle));
+            }
+            vendor.status = if active { VendorStatus::Active } else { VendorStatus::Inactive };
+            vendor.updated_at = now;
+            Ok(())
+        })
+    }
+
+    pub fn create_subscription(&self, payer: Principal, args: CreateSubscriptionArgs) -> EscrowResult<u64> {
+        let now = self.clock.now();
+        self.validate_terms(payer, args.payee, args.ledger, args.amount)?;
+        args.validate()?;
+        Ok(state::mutate(|s| {
+            let id = s.next_subscription_id;
+            s.next_subscription_id += 1;
+            limits::record_creation(s, payer, now);
+            let subscription = Subscription::new(id, payer, args, now);
+            notifications::notify(
+                s,
+                subscription.payee,
+                format!("You have been invited to recurring escrow {}. Amount per period: {}", id, subscription.amount),
+                None,
+                now,
+            );
+            s.subscriptions.insert(id, subscription);
+            id
+        }))
+    }
+
+    /// The payee agrees to the series. The first period is opened on the next
+    /// sweep and the rest follow every `interval_secs`.
+    pub fn accept_subscription(&self, caller: Principal, subscription_id: u64) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let subscription = s.subscriptions.get_mut(&subscription_id).ok_or(EscrowError::NotFound)?;
+            if caller != subscription.payee {
+                return Err(EscrowError::Unauthorized);
+            }
+            if subscription.status != SubscriptionStatus::Pending {
+                return Err(EscrowError::InvalidArgument("subscription is not pending".to_string()));
+            }
+            subscription.status = SubscriptionStatus::Active;
+            subscription.next_due = Some(now);
+            subscription.updated_at = now;
+            let payer = subscription.payer;
+            notifications::notify(s, payer, format!("Recurring escrow {} accepted by the payee", subscription_id), None, now);
+            Ok(())
+        })
+    }
+
+    /// Stops the series. Periods that were already funded carry on as ordinary
+    /// escrows; nothing is charged for periods that were never opened.
+    pub fn cancel_subscription(&self, caller: Principal, subscription_id: u64) -> EscrowResult<()> {
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let subscription = s.subscriptions.get_mut(&subscription_id).ok_or(EscrowError::NotFound)?;
+            if caller != subscription.payer && caller != subscription.payee {
+                return Err(EscrowError::Unauthorized);
+            }
+            if !matches!(subscription.status, SubscriptionStatus::Pending | SubscriptionStatus::Active) {
+                return Err(EscrowError::InvalidArgument("subscription has already ended".to_string()));
+            }
+            subscription.status = SubscriptionStatus::Cancelled;
+            subscription.next_due = None;
+            subscription.updated_at = now;
+            let (payer, payee) = (subscription.payer, subscription.payee);
+            let message = format!("Recurring escrow {} cancelled", subscription_id);
+            notifications::notify(s, payer, message.clone(), None, now);
+            notifications::notify(s, payee, message, None, now);
+            Ok(())
+        })
+    }
+
+    pub async fn open_due_subscription_periods(&self) {
+        if pause::check(PauseScope::Funding).is_err() {
+            return;
+        }
+        let now = self.clock.now();
+        let claimed: Vec<(Subscription, u32, u64)> = state::mutate(|s| {
+            let due: Vec<u64> = s.subscriptions.values().filter(|sub| sub.is_due(now)).map(|sub| sub.id).collect();
+            let mut claimed = Vec::new();
+            for id in due {
+                let escrow_id = s.allocate_escrow_id();
+                let Some(subscription) = s.subscriptions.get_mut(&id) else {
+                    continue;
+                };
+                let period = subscription.claim_period(now);
+                claimed.push((subscription.clone(), period, escrow_id));
+            }
+            claimed
+        });
+        let opened = !claimed.is_empty();
+        for (subscription, period, escrow_id) in claimed {
+            self.open_subscription_period(subscription, period, escrow_id, now).await;
+        }
+        if opened {
+            monitoring::record_call_cost("subscription_sweep", self.clock.now());
+        }
+    }
+
+    /// Funds the period escrow from the payer's allowance first and only records
+    /// it once the money is in, so a failed pull leaves no half-open contract.
+    /// The pull carries the period's memo and the time it was claimed. A period
+    /// whose parties no longer pass the KYC thresholds counts as missed.
+    async fn open_subscription_period(&self, subscription: Subscription, period: u32, escrow_id: u64, claimed_at: u64) {
+        let opened: EscrowResult<(EscrowContract, u64)> = async {
+            let args = CreateEscrowArgs {
+                payee: subscription.payee,
+                ledger: subscription.ledger,
+                amount: subscription.amount,
+                conditions: format!("{} (period {})", subscription.conditions, period),
+                hash_lock: None,
+                release_policy: None,
+                splits: None,
+                inspection_window_secs: None,
+                milestones: None,
+                required_evidence: None,
+                vendor_id: None,
+            };
+            let contract = EscrowContract::new(escrow_id, subscription.payer, args, None, self.clock.now())?;
+            state::read(|s| {
+                kyc::check(s, subscription.payer, subscription.payee, subscription.ledger, subscription.amount, self.clock.now())
+            })?;
+            let block_index = self
+                .ledger
+                .transfer_from(
+                    subscription.ledger,
+                    Account::from(subscription.payer),
+                    ledger::escrow_account(self.canister_id, escrow_id),
+                    subscription.amount,
+                    Some(subscription::period_memo(subscription.id, period)),
+                    Some(claimed_at),
+                )
+                .await?;
+            Ok((contract, block_index))
+        }
+        .await;
+        let now = self.clock.now();
+        state::mutate(|s| {
+            let message = match opened {
+                Ok((mut contract, block_index)) => {
+                    contract.subscription_id = Some(subscription.id);
+                    contract.fee_bps = s.platform_fee_bps;
+                    contract.transition(ContractStatus::Active, self.canister_id, EscrowEvent::Funded { block_index }, now);
+                    s.escrows.insert(escrow_id, contract);
+                    if let Some(sub) = s.subscriptions.get_mut(&subscription.id) {
+                        sub.record_funded(escrow_id, now);
+                    }
+                    format!("Period {} of recurring escrow {} funded as contract {}", period, subscription.id, escrow_id)
+                }
+                Err(e) => {
+                    let lapsed = s
+                        .subscriptions
+                        .get_mut(&subscription.id)
+                        .is_some_and(|sub| sub.record_missed(format!("{:?}", e), now));
+                    if lapsed {
+                        format!(
+                            "Period {} of recurring escrow {} could not be funded; the series stopped after {} missed periods in a row",
+                            period,
+                            subscription.id,
+                            subscription::MAX_MISSED_PERIODS
+                        )
+                    } else {
+                        format!("Period {} of recurring escrow {} could not be funded", period, subscription.id)
+                    }
+                }
+            };
+            notifications::notify(s, subscription.payer, message.clone(), None, now);
+            notifications::notify(s, subscription.payee, message, None, now);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escrow::Milestone;
+    use crate::hashlock::HashLockArgs;
+    use crate::mock::{block_on, poll_once, MockClock, MockLedger};
+    use std::pin::pin;
+    use std::task::Poll;
+    use crate::state::State;
+    use sha2::{Digest, Sha256};
+
+    const FEE: u64 = 10;
+    const AMOUNT: u64 = 10_000;
+    const START: u64 = 1_700_000_000_000_000_000;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn payer() -> Principal {
+        principal(1)
+    }
+
+    fn payee() -> Principal {
+        principal(2)
+    }
+
+    fn token() -> Principal {
+        principal(9)
+    }
+
+    fn setup() -> Service<MockLedger, MockClock> {
+        state::replace(State::default());
+        let canister_id = principal(100);
+        let service = Service {
+            ledger: MockLedger::new(FEE, canister_id),
+            clock: MockClock::new(START),
+            canister_id,
+        };
+        block_on(service.add_ledger(token())).unwrap();
+        service.ledger.mint(Account::from(payer()), 1_000_000);
+        service
+    }
+
+    fn args() -> CreateEscrowArgs {
+        CreateEscrowArgs {
+            payee: payee(),
+            ledger: token(),
+            amount: AMOUNT,
+            conditions: "Deliver the goods".to_string(),
+            hash_lock: None,
+            release_policy: None,
+            splits: None,
+            inspection_window_secs: None,
+            milestones: None,
+            required_evidence: None,
+            vendor_id: None,
+        }
+    }
+
+    fn subscription_args() -> CreateSubscriptionArgs {
+        CreateSubscriptionArgs {
+            payee: payee(),
+            ledger: token(),
+            amount: AMOUNT,
+            conditions: "Monthly retainer".to_string(),
+            interval_secs: 3_600,
+            max_periods: None,
+        }
+    }
+
+    fn funded(service: &Service<MockLedger, MockClock>, args: CreateEscrowArgs) -> u64 {
+        let id = service.create_escrow(payer(), args).unwrap();
+        service.accept_escrow(payee(), id).unwrap();
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        block_on(service.fund_escrow(payer(), id)).unwrap();
+        id
+    }
+
+    fn status(id: u64) -> ContractStatus {
+        state::read(|s| s.escrows[&id].status)
+    }
+
+    fn escrow_balance(service: &Service<MockLedger, MockClock>, id: u64) -> u64 {
+        service.ledger.balance(ledger::escrow_account(service.canister_id, id))
+    }
+
+    #[test]
+    fn funding_and_release_move_the_amount_less_fees() {
+        let service = setup();
+        let id = funded(&service, args());
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), AMOUNT);
+
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+        assert_eq!(escrow_balance(&service, id), 0);
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT - FEE);
+        assert_eq!(service.ledger.total_supply(), 1_000_000);
+    }
+
+    #[test]
+    fn platform_fee_is_withheld_and_collected() {
+        let service = setup();
+        state::mutate(|s| s.platform_fee_bps = 250);
+        let id = funded(&service, args());
+
+        block_on(service.release_funds(payer(), id)).unwrap();
+        let platform_fee = AMOUNT * 250 / 10_000;
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT - platform_fee - FEE);
+        assert_eq!(service.ledger.balance(Account::from(service.canister_id)), platform_fee - FEE);
+        assert_eq!(escrow_balance(&service, id), 0);
+        let collected = state::read(|s| {
+            s.escrows[&id]
+                .history
+                .iter()
+                .any(|h| matches!(h.event, EscrowEvent::FeeCollected { amount, .. } if amount == platform_fee - FEE))
+        });
+        assert!(collected);
+    }
+
+    #[test]
+    fn only_the_parties_may_drive_the_contract() {
+        let service = setup();
+        let id = service.create_escrow(payer(), args()).unwrap();
+        assert_eq!(service.accept_escrow(payer(), id), Err(EscrowError::Unauthorized));
+        service.accept_escrow(payee(), id).unwrap();
+        assert_eq!(block_on(service.fund_escrow(payee(), id)), Err(EscrowError::Unauthorized));
+
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        block_on(service.fund_escrow(payer(), id)).unwrap();
+        assert_eq!(block_on(service.release_funds(payee(), id)), Err(EscrowError::Unauthorized));
+        assert_eq!(service.dispute_contract(principal(3), id, "no".to_string()), Err(EscrowError::Unauthorized));
+        assert_eq!(status(id), ContractStatus::Active);
+    }
+
+    #[test]
+    fn terminal_states_are_final() {
+        let service = setup();
+        let id = funded(&service, args());
+        block_on(service.release_funds(payer(), id)).unwrap();
+
+        let released = Err(EscrowError::InvalidStatus(ContractStatus::Released));
+        assert_eq!(block_on(service.release_funds(payer(), id)), released);
+        assert_eq!(block_on(service.refund_funds(payee(), id)), Err(EscrowError::InvalidStatus(ContractStatus::Released)));
+        assert_eq!(service.dispute_contract(payer(), id, "late".to_string()), Err(EscrowError::InvalidStatus(ContractStatus::Released)));
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT - FEE);
+    }
+
+    #[test]
+    fn failed_funding_leaves_the_contract_accepted() {
+        let service = setup();
+        let id = service.create_escrow(payer(), args()).unwrap();
+        service.accept_escrow(payee(), id).unwrap();
+        // Approving the bare amount does not cover the transfer fee.
+        service.ledger.approve(payer(), AMOUNT).unwrap();
+        assert!(matches!(block_on(service.fund_escrow(payer(), id)), Err(EscrowError::Ledger(_))));
+        assert_eq!(status(id), ContractStatus::Accepted);
+        assert_eq!(escrow_balance(&service, id), 0);
+    }
+
+    #[test]
+    fn failed_release_keeps_the_funds_in_escrow() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.fail.set(true);
+        assert!(block_on(service.release_funds(payer(), id)).is_err());
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), AMOUNT);
+
+        service.ledger.fail.set(false);
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+    }
+
+    #[test]
+    fn dispute_resolution_refunds_the_payer() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.dispute_contract(payee(), id, "cannot deliver".to_string()).unwrap();
+        assert_eq!(block_on(service.release_funds(payer(), id)), Err(EscrowError::InvalidStatus(ContractStatus::Disputed)));
+
+        block_on(service.resolve_dispute(principal(3), id, DisputeResolution::RefundToPayer)).unwrap();
+        assert_eq!(status(id), ContractStatus::Refunded);
+        // Approval, funding and refund each cost one fee.
+        assert_eq!(service.ledger.balance(Account::from(payer())), 1_000_000 - 3 * FEE);
+    }
+
+    #[test]
+    fn inspection_window_runs_on_the_clock() {
+        let service = setup();
+        let id = funded(&service, CreateEscrowArgs { inspection_window_secs: Some(3_600), ..args() });
+        service.mark_delivered(payee(), id).unwrap();
+
+        service.clock.advance_secs(3_599);
+        assert!(matches!(block_on(service.release_after_inspection(payee(), id)), Err(EscrowError::InvalidArgument(_))));
+        service.clock.advance_secs(1);
+        block_on(service.release_after_inspection(payee(), id)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+    }
+
+    #[test]
+    fn expired_hash_locks_are_refunded_by_the_sweep() {
+        let service = setup();
+        let lock = HashLockArgs {
+            hash: ByteBuf::from(Sha256::digest(b"secret").to_vec()),
+            expires_at: START + 60 * 1_000_000_000,
+        };
+        let id = funded(&service, CreateEscrowArgs { hash_lock: Some(lock), ..args() });
+
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(block_on(service.reclaim_expired_escrow(payer(), id)), Err(EscrowError::HashLockNotExpired));
+
+        service.clock.advance_secs(60);
+        assert_eq!(
+            block_on(service.confirm_delivery(payee(), id, ByteBuf::from(b"secret".to_vec()))),
+            Err(EscrowError::HashLockExpired)
+        );
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(status(id), ContractStatus::Refunded);
+        assert_eq!(escrow_balance(&service, id), 0);
+    }
+
+    #[test]
+    fn export_lists_only_the_callers_history() {
+        use crate::export::{self, ExportFormat, ExportRange};
+
+        let service = setup();
+        let id = funded(&service, args());
+        service.dispute_contract(payer(), id, "=late, damaged".to_string()).unwrap();
+
+        let csv = state::read(|s| export::export(s, payer(), ExportFormat::Csv, &ExportRange::default()));
+        let lines: Vec<&str> = csv.body.lines().collect();
+        assert_eq!(lines.len(), 5, "header plus Created, Accepted, Funded, Disputed");
+        assert!(lines[3].contains(",Funded,") && lines[3].ends_with(",10000,0,"));
+        assert!(lines[4].ends_with(",\"'=late, damaged\""));
+        assert_eq!(csv.next_cursor, None);
+
+        let json = state::read(|s| export::export(s, payee(), ExportFormat::Json, &ExportRange::default()));
+        assert_eq!(json.body.matches("\"contract_id\"").count(), 4);
+
+        let later = ExportRange { from: Some(service.clock.now() + 1), ..Default::default() };
+        assert_eq!(state::read(|s| export::export(s, payer(), ExportFormat::Json, &later)).body, "[]");
+        assert_eq!(state::read(|s| export::export(s, principal(3), ExportFormat::Json, &ExportRange::default())).body, "[]");
+    }
+
+    #[test]
+    fn settled_parties_rate_each_other_once() {
+        let service = setup();
+        let released = funded(&service, args());
+        assert_eq!(
+            service.rate_counterparty(payer(), released, 5, String::new()),
+            Err(EscrowError::InvalidStatus(ContractStatus::Active))
+        );
+        block_on(service.release_funds(payer(), released)).unwrap();
+
+        let refunded = funded(&service, args());
+        service.dispute_contract(payer(), refunded, "no show".to_string()).unwrap();
+        block_on(service.resolve_dispute(principal(3), refunded, DisputeResolution::RefundToPayer)).unwrap();
+
+        assert!(matches!(service.rate_counterparty(payer(), released, 6, String::new()), Err(EscrowError::InvalidArgument(_))));
+        assert_eq!(service.rate_counterparty(principal(3), released, 5, String::new()), Err(EscrowError::Unauthorized));
+        service.rate_counterparty(payer(), released, 5, "Fast delivery".to_string()).unwrap();
+        service.rate_counterparty(payer(), refunded, 2, String::new()).unwrap();
+        service.rate_counterparty(payee(), released, 4, String::new()).unwrap();
+        assert!(matches!(service.rate_counterparty(payer(), released, 1, String::new()), Err(EscrowError::InvalidArgument(_))));
+
+        let rep = state::read(|s| reputation::reputation(s, payee()));
+        assert_eq!((rep.completed, rep.refunded, rep.disputed), (1, 1, 1));
+        assert_eq!(rep.dispute_rate_bps, 5_000);
+        assert_eq!(rep.rating_count, 2);
+        assert_eq!(rep.average_rating, Some(3.5));
+        assert_eq!(rep.volume[0].band, reputation::VolumeBand::Low);
+        assert_eq!(state::read(|s| reputation::reputation(s, payer())).average_rating, Some(4.0));
+    }
+
+    #[test]
+    fn unfunded_contracts_cannot_be_rated_or_count_towards_reputation() {
+        let service = setup();
+        let cancelled = service.create_escrow(payer(), args()).unwrap();
+        assert_eq!(block_on(service.refund_funds(payer(), cancelled)), Ok(None));
+        assert_eq!(status(cancelled), ContractStatus::Refunded);
+
+        assert!(matches!(service.rate_counterparty(payer(), cancelled, 1, String::new()), Err(EscrowError::InvalidArgument(_))));
+        let rep = state::read(|s| reputation::reputation(s, payee()));
+        assert_eq!((rep.completed, rep.refunded, rep.rating_count), (0, 0, 0));
+        assert!(rep.volume.is_empty());
+
+        // Volume is banded in whole tokens of the ledger's own decimals.
+        state::mutate(|s| s.ledgers.get_mut(&token()).unwrap().decimals = Some(2));
+        let released = funded(&service, args());
+        block_on(service.release_funds(payer(), released)).unwrap();
+        let rep = state::read(|s| reputation::reputation(s, payee()));
+        assert_eq!(rep.volume[0].band, reputation::VolumeBand::Medium);
+    }
+
+    #[test]
+    fn vendor_escrows_require_an_active_vendor_and_pay_its_account() {
+        use crate::vendor::{PayoutAccount, VendorArgs};
+
+        let service = setup();
+        let payout = Account { owner: principal(4), subaccount: Some([7; 32]) };
+        let vendor = VendorArgs {
+            handle: "acme-tools".to_string(),
+            business_name: "Acme Tools Ltd".to_string(),
+            payout_accounts: vec![PayoutAccount { ledger: token(), account: payout }],
+            categories: vec![Industry::Freelance],
+        };
+        let to_canister = PayoutAccount { ledger: token(), account: Account::from(service.canister_id) };
+        assert!(matches!(
+            service.register_vendor(payee(), VendorArgs { payout_accounts: vec![to_canister], ..vendor.clone() }),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+        service.register_vendor(payee(), vendor.clone()).unwrap();
+        assert!(matches!(service.register_vendor(principal(3), vendor), Err(EscrowError::InvalidArgument(_))));
+
+        let vendor_args = |handle: &str| CreateEscrowArgs { vendor_id: Some(handle.to_string()), ..args() };
+        assert_eq!(
+            service.create_escrow(payer(), vendor_args("nobody")),
+            Err(EscrowError::VendorNotFound("nobody".to_string()))
+        );
+        assert!(matches!(
+            service.create_escrow(payer(), CreateEscrowArgs { payee: principal(3), ..vendor_args("acme-tools") }),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+        service.set_vendor_active(payee(), false).unwrap();
+        assert_eq!(
+            service.create_escrow(payer(), vendor_args("acme-tools")),
+            Err(EscrowError::VendorInactive("acme-tools".to_string()))
+        );
+        service.set_vendor_active(payee(), true).unwrap();
+
+        let id = funded(&service, vendor_args("acme-tools"));
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(service.ledger.balance(payout), AMOUNT - FEE);
+        assert_eq!(service.ledger.balance(Account::from(payee())), 0);
+    }
+
+    #[test]
+    fn kyc_thresholds_block_unattested_parties() {
+        use crate::kyc::{self, KycLevel, KycThreshold};
+
+        let service = setup();
+        let issuer = principal(5);
+        let now = service.clock.now();
+        let hour = 3_600 * 1_000_000_000;
+        state::mutate(|s| {
+            s.kyc_thresholds = vec![
+                KycThreshold { ledger: token(), min_amount: AMOUNT, level: KycLevel::Basic },
+                KycThreshold { ledger: token(), min_amount: 10 * AMOUNT, level: KycLevel::Enhanced },
+            ];
+            assert_eq!(kyc::attest(s, issuer, payer(), KycLevel::Basic, now + hour, now), Err(EscrowError::Unauthorized));
+            s.kyc_issuers.insert(issuer);
+            kyc::attest(s, issuer, payer(), KycLevel::Basic, now + hour, now).unwrap();
+            kyc::attest(s, issuer, payee(), KycLevel::Basic, now + 2 * hour, now).unwrap();
+        });
+
+        assert!(service.create_escrow(payer(), CreateEscrowArgs { amount: AMOUNT - 1, ..args() }).is_ok());
+        assert_eq!(
+            service.create_escrow(payer(), CreateEscrowArgs { amount: 10 * AMOUNT, ..args() }),
+            Err(EscrowError::KycRequired { principal: payer(), level: KycLevel::Enhanced })
+        );
+
+        // The payer's attestation runs out between creation and funding.
+        let id = service.create_escrow(payer(), args()).unwrap();
+        service.accept_escrow(payee(), id).unwrap();
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        service.clock.advance_secs(3_600);
+        assert_eq!(
+            block_on(service.fund_escrow(payer(), id)),
+            Err(EscrowError::KycRequired { principal: payer(), level: KycLevel::Basic })
+        );
+        state::mutate(|s| kyc::attest(s, issuer, payer(), KycLevel::Basic, service.clock.now() + hour, service.clock.now())).unwrap();
+        state::mutate(|s| s.kyc_issuers.remove(&issuer));
+        assert_eq!(
+            block_on(service.fund_escrow(payer(), id)),
+            Err(EscrowError::KycRequired { principal: payer(), level: KycLevel::Basic })
+        );
+        state::mutate(|s| s.kyc_issuers.insert(issuer));
+        block_on(service.fund_escrow(payer(), id)).unwrap();
+    }
+
+    #[test]
+    fn reserve_shortfalls_pause_payouts_above_the_threshold() {
+        use crate::pause::PauseScope;
+
+        let service = setup();
+        let first = funded(&service, args());
+        let second = funded(&service, args());
+        let report = block_on(service.reconcile_reserves());
+        assert!(report.discrepancies.is_empty() && !report.paused_payouts);
+        assert_eq!((report.ledgers[0].locked, report.ledgers[0].held), (2 * AMOUNT, 2 * AMOUNT));
+
+        state::mutate(|s| s.reserve_deficit_thresholds.insert(token(), 100));
+        service.ledger.burn(ledger::escrow_account(service.canister_id, first), 100).unwrap();
+        let report = block_on(service.reconcile_reserves());
+        assert_eq!(report.ledgers[0].deficit, 100);
+        assert_eq!(report.discrepancies.len(), 1);
+        assert!(!report.paused_payouts);
+
+        service.ledger.burn(ledger::escrow_account(service.canister_id, second), 1).unwrap();
+        assert!(block_on(service.reconcile_reserves()).paused_payouts);
+        assert_eq!(block_on(service.release_funds(payer(), second)), Err(EscrowError::Paused(PauseScope::Releases)));
+        assert_eq!(block_on(service.refund_funds(payee(), second)), Err(EscrowError::Paused(PauseScope::Refunds)));
+        assert!(state::read(|s| s.reserve_report.as_ref().is_some_and(|r| r.discrepancies.len() == 2)));
+    }
+
+    #[test]
+    fn a_lost_reply_does_not_pay_twice() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.lose_replies.set(true);
+        assert!(block_on(service.release_funds(payer(), id)).is_err());
+        // The money left, but the contract waits for a confirmation.
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), 0);
+        assert_eq!(block_on(service.refund_funds(payee(), id)), Err(EscrowError::PayoutPending));
+        assert_eq!(service.dispute_contract(payer(), id, String::new()), Err(EscrowError::PayoutPending));
+
+        service.ledger.lose_replies.set(false);
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+        assert_eq!(service.ledger.payouts_from(ledger::escrow_subaccount(id)), 1);
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT - FEE);
+        assert!(state::read(|s| s.pending_transfers.is_empty()));
+    }
+
+    #[test]
+    fn queued_payouts_are_retried_with_backoff() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.fail.set(true);
+        assert!(block_on(service.refund_funds(payee(), id)).is_err());
+        block_on(service.retry_pending_transfers());
+        let attempts = || state::read(|s| s.pending_transfers.values().map(|t| t.attempts).sum::<u32>());
+        assert_eq!(attempts(), 1, "not due again yet");
+
+        service.clock.advance_secs(30);
+        block_on(service.retry_pending_transfers());
+        assert_eq!(attempts(), 2);
+        service.clock.advance_secs(30);
+        block_on(service.retry_pending_transfers());
+        assert_eq!(attempts(), 2, "backoff doubled");
+
+        service.ledger.fail.set(false);
+        service.clock.advance_secs(30);
+        block_on(service.retry_pending_transfers());
+        assert_eq!(status(id), ContractStatus::Refunded);
+        assert_eq!(escrow_balance(&service, id), 0);
+        assert!(state::read(|s| s.pending_transfers.is_empty()));
+    }
+
+    #[test]
+    fn queued_payouts_are_only_given_up_outside_the_dedup_window() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.fail.set(true);
+        assert!(block_on(service.release_funds(payer(), id)).is_err());
+        for _ in 0..22 {
+            service.clock.advance_secs(60 * 60);
+            block_on(service.retry_pending_transfers());
+        }
+        let queued = state::read(|s| s.pending_transfers.values().next().cloned()).unwrap();
+        assert!(queued.attempts > 8, "still retried after {} attempts", queued.attempts);
+
+        service.clock.advance_secs(60 * 60);
+        block_on(service.retry_pending_transfers());
+        assert!(state::read(|s| s.pending_transfers.is_empty()));
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), AMOUNT);
+    }
+
+    #[test]
+    fn a_contract_pays_out_once_while_a_payout_is_in_flight() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.suspend.set(true);
+        let mut release = pin!(service.release_funds(payer(), id));
+        assert!(poll_once(release.as_mut()).is_pending());
+
+        assert_eq!(block_on(service.release_funds(payer(), id)), Err(EscrowError::ContractBusy));
+        assert_eq!(block_on(service.refund_funds(payee(), id)), Err(EscrowError::ContractBusy));
+        block_on(service.retry_pending_transfers());
+        assert_eq!(service.ledger.payouts_from(ledger::escrow_subaccount(id)), 0);
+
+        assert!(matches!(poll_once(release.as_mut()), Poll::Ready(Ok(_))));
+        assert_eq!(status(id), ContractStatus::Released);
+        assert_eq!(service.ledger.payouts_from(ledger::escrow_subaccount(id)), 1);
+        assert_eq!(block_on(service.refund_funds(payee(), id)), Err(EscrowError::InvalidStatus(ContractStatus::Released)));
+    }
+
+    #[test]
+    fn an_abandoned_call_releases_its_lock() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.ledger.suspend.set(true);
+        {
+            // Dropping the future is what the cleanup after a trap does.
+            let mut release = pin!(service.release_funds(payer(), id));
+            assert!(poll_once(release.as_mut()).is_pending());
+        }
+        assert_eq!(status(id), ContractStatus::Active);
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+        assert_eq!(service.ledger.payouts_from(ledger::escrow_subaccount(id)), 1);
+    }
+
+    #[test]
+    fn a_replayed_create_returns_the_first_escrow() {
+        let service = setup();
+        let key = || Some("create-1".to_string());
+        let create = |args: CreateEscrowArgs| {
+            let request = Request::new("create_escrow", &args);
+            service.idempotent(payer(), key(), request, || service.create_escrow(payer(), args))
+        };
+        let id = create(args()).unwrap();
+        assert_eq!(create(args()), Ok(id));
+        assert_eq!(state::read(|s| s.escrows.len()), 1);
+
+        let other = CreateEscrowArgs { amount: AMOUNT * 2, ..args() };
+        assert!(matches!(create(other), Err(EscrowError::InvalidArgument(_))));
+        // Another caller's keys are their own.
+        let request = Request::new("create_escrow", args());
+        let theirs = service.idempotent(payee(), key(), request, || service.create_escrow(payee(), CreateEscrowArgs { payee: payer(), ..args() }));
+        assert_ne!(theirs.unwrap(), id);
+
+        service.clock.advance_secs(24 * 60 * 60);
+        assert_ne!(create(args()).unwrap(), id, "keys expire");
+    }
+
+    #[test]
+    fn a_failed_call_can_be_retried_under_its_key() {
+        let service = setup();
+        let id = service.create_escrow(payer(), args()).unwrap();
+        service.accept_escrow(payee(), id).unwrap();
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        let fund = || {
+            let request = Request::new("fund_escrow", id);
+            block_on(service.idempotent_async(payer(), Some("fund".to_string()), request, service.fund_escrow(payer(), id)))
+        };
+        service.ledger.fail.set(true);
+        assert!(fund().is_err());
+        service.ledger.fail.set(false);
+        let block_index = fund().unwrap();
+        assert_eq!(fund(), Ok(block_index));
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), AMOUNT);
+    }
+
+    fn history(id: u64) -> Vec<EscrowEvent> {
+        state::read(|s| s.escrows[&id].history.iter().map(|e| e.event.clone()).collect())
+    }
+
+    #[test]
+    fn a_confirmed_cancellation_pays_out_as_agreed() {
+        let service = setup();
+        let id = funded(&service, args());
+        let payer_before = service.ledger.balance(Account::from(payer()));
+        service.propose_cancellation(payee(), id, 3_000, "half done".to_string()).unwrap();
+
+        assert!(matches!(
+            block_on(service.confirm_cancellation(payer(), id, 2_000)),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+        assert_eq!(block_on(service.confirm_cancellation(payee(), id, 3_000)), Err(EscrowError::Unauthorized));
+        block_on(service.confirm_cancellation(payer(), id, 3_000)).unwrap();
+
+        assert_eq!(status(id), ContractStatus::Refunded);
+        assert_eq!(service.ledger.balance(Account::from(payee())), 3_000 - FEE);
+        assert_eq!(service.ledger.balance(Account::from(payer())), payer_before + AMOUNT - 3_000 - FEE);
+        assert_eq!(escrow_balance(&service, id), 0);
+        let events = history(id);
+        assert!(matches!(events[events.len() - 4], EscrowEvent::CancellationProposed { payee_amount: 3_000, .. }));
+        assert!(matches!(events[events.len() - 3], EscrowEvent::CancellationConfirmed { payee_amount: 3_000 }));
+        assert!(matches!(events[events.len() - 2], EscrowEvent::CancellationPayout { amount, .. } if amount == 3_000 - FEE));
+        assert!(matches!(events[events.len() - 1], EscrowEvent::Refunded { block_index: Some(_) }));
+    }
+
+    #[test]
+    fn a_confirmed_cancellation_binds_both_parties() {
+        let service = setup();
+        let id = funded(&service, args());
+        service.propose_cancellation(payer(), id, 0, String::new()).unwrap();
+        // The payee answers with a counter-offer, which only they can withdraw.
+        service.propose_cancellation(payee(), id, 5_000, String::new()).unwrap();
+        assert_eq!(service.withdraw_cancellation(payer(), id), Err(EscrowError::Unauthorized));
+        assert!(matches!(
+            service.propose_cancellation(payer(), id, AMOUNT, String::new()),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+
+        service.ledger.fail.set(true);
+        assert!(block_on(service.confirm_cancellation(payer(), id, 5_000)).is_err());
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(block_on(service.release_funds(payer(), id)), Err(EscrowError::CancellationAgreed));
+        assert_eq!(block_on(service.refund_funds(payee(), id)).unwrap_err(), EscrowError::CancellationAgreed);
+        assert_eq!(service.dispute_contract(payee(), id, String::new()), Err(EscrowError::CancellationAgreed));
+        assert_eq!(service.withdraw_cancellation(payee(), id), Err(EscrowError::CancellationAgreed));
+
+        service.ledger.fail.set(false);
+        service.clock.advance_secs(30);
+        block_on(service.retry_pending_transfers());
+        assert_eq!(status(id), ContractStatus::Refunded);
+        assert_eq!(service.ledger.payouts_from(ledger::escrow_subaccount(id)), 2);
+        assert_eq!(escrow_balance(&service, id), 0);
+    }
+
+    #[test]
+    fn an_approved_top_up_raises_the_amount_and_versions_the_terms() {
+        let service = setup();
+        let id = funded(&service, args());
+        let original = state::read(|s| s.escrows[&id].terms_versions.clone());
+        assert_eq!(original.len(), 1);
+
+        let top_up = AmendmentChange::TopUp { amount: 2_000 };
+        service.propose_amendment(payee(), id, top_up.clone()).unwrap();
+        assert_eq!(block_on(service.approve_amendment(payee(), id, top_up.clone())).unwrap_err(), EscrowError::Unauthorized);
+        assert!(matches!(
+            block_on(service.approve_amendment(payer(), id, AmendmentChange::TopUp { amount: 20 })),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+        // Without an allowance for the deposit nothing changes.
+        assert!(block_on(service.approve_amendment(payer(), id, top_up.clone())).is_err());
+        assert_eq!(state::read(|s| s.escrows[&id].amount), AMOUNT);
+
+        service.ledger.approve(payer(), 2_000 + FEE).unwrap();
+        service.clock.advance_secs(60);
+        let version = block_on(service.approve_amendment(payer(), id, top_up)).unwrap();
+        assert_eq!(version.version, 2);
+        assert_eq!(version.terms.amount, AMOUNT + 2_000);
+        assert_ne!(version.hash, original[0].hash);
+        assert_eq!(escrow_balance(&service, id), AMOUNT + 2_000);
+
+        let contract = state::read(|s| s.escrows[&id].clone());
+        assert!(contract.amendment.is_none());
+        let before = amendment::in_force_at(&contract, START).unwrap().unwrap();
+        assert_eq!((before.version, before.hash), (1, original[0].hash.clone()));
+        assert_eq!(amendment::in_force_at(&contract, service.clock.now()).unwrap().unwrap().version, 2);
+
+        block_on(service.release_funds(payer(), id)).unwrap();
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT + 2_000 - FEE);
+    }
+
+    #[test]
+    fn deadlines_and_milestones_change_only_by_agreement() {
+        let service = setup();
+        let lock = HashLockArgs {
+            hash: ByteBuf::from(Sha256::digest(b"secret").to_vec()),
+            expires_at: START + 60 * 1_000_000_000,
+        };
+        let id = funded(&service, CreateEscrowArgs { hash_lock: Some(lock), ..args() });
+        let plain = funded(&service, args());
+        let extend = AmendmentChange::SetDeadline { expires_at: START + 3_600 * 1_000_000_000 };
+        assert!(matches!(
+            service.propose_amendment(payer(), plain, extend.clone()),
+            Err(EscrowError::InvalidArgument(_))
+        ));
+
+        service.propose_amendment(payee(), id, extend.clone()).unwrap();
+        assert_eq!(service.withdraw_amendment(payer(), id), Err(EscrowError::Unauthorized));
+        block_on(service.approve_amendment(payer(), id, extend)).unwrap();
+        service.clock.advance_secs(60);
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(status(id), ContractStatus::Active, "the old deadline no longer applies");
+
+        let milestone = AmendmentChange::AddMilestone(Milestone {
+            title: "Extra round of revisions".to_string(),
+            due_at: None,
+        });
+        service.propose_amendment(payer(), id, milestone.clone()).unwrap();
+        service.withdraw_amendment(payer(), id).unwrap();
+        assert_eq!(block_on(service.approve_amendment(payee(), id, milestone)).unwrap_err(), EscrowError::NotFound);
+        let contract = state::read(|s| s.escrows[&id].clone());
+        assert!(contract.milestones.is_empty());
+        assert_eq!(contract.terms_versions.len(), 2);
+        assert_eq!(contract.terms_versions[1].terms.deadline, Some(START + 3_600 * 1_000_000_000));
+    }
+
+    #[test]
+    fn cancellations_too_small_to_pay_out_are_refused() {
+        let service = setup();
+        let id = funded(&service, args());
+        for payee_amount in [1, FEE, AMOUNT - FEE, AMOUNT] {
+            assert!(
+                matches!(
+                    service.propose_cancellation(payee(), id, payee_amount, String::new()),
+                    Err(EscrowError::InvalidArgument(_))
+                ),
+                "payee_amount {}",
+                payee_amount
+            );
+        }
+        service.propose_cancellation(payee(), id, FEE + 1, String::new()).unwrap();
+        service.propose_cancellation(payee(), id, AMOUNT - FEE - 1, String::new()).unwrap();
+        block_on(service.confirm_cancellation(payer(), id, AMOUNT - FEE - 1)).unwrap();
+        assert_eq!(status(id), ContractStatus::Refunded);
+        assert_eq!(escrow_balance(&service, id), 0);
+        assert!(state::read(|s| s.pending_transfers.is_empty()));
+    }
+
+    #[test]
+    fn a_revealed_preimage_is_released_even_after_expiry() {
+        use crate::pause::PauseScope;
+
+        let service = setup();
+        let lock = HashLockArgs {
+            hash: ByteBuf::from(Sha256::digest(b"secret").to_vec()),
+            expires_at: START + 60 * 1_000_000_000,
+        };
+        let id = funded(&service, CreateEscrowArgs { hash_lock: Some(lock), ..args() });
+        let pause = |scope| {
+            let info = PauseInfo { scope, reason: String::new(), paused_by: payer(), paused_at: START };
+            state::mutate(|s| s.paused.insert(scope, info));
+        };
+        pause(PauseScope::Releases);
+        assert_eq!(
+            block_on(service.confirm_delivery(payee(), id, ByteBuf::from(b"secret".to_vec()))),
+            Err(EscrowError::Paused(PauseScope::Releases))
+        );
+
+        service.clock.advance_secs(60);
+        pause(PauseScope::Refunds);
+        let logs = state::read(|s| s.logs.len());
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(state::read(|s| s.logs.len()), logs, "paused payouts are not logged as failures");
+        state::mutate(|s| s.paused.remove(&PauseScope::Refunds));
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(block_on(service.reclaim_expired_escrow(payer(), id)), Err(EscrowError::PreimageRevealed));
+
+        state::mutate(|s| s.paused.remove(&PauseScope::Releases));
+        block_on(service.refund_expired_hash_locks());
+        assert_eq!(status(id), ContractStatus::Released);
+        assert_eq!(service.ledger.balance(Account::from(payee())), AMOUNT - FEE);
+    }
+
+    #[test]
+    fn webhook_replies_are_reduced_to_their_status() {
+        use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse, TransformArgs};
+
+        let reply = |date: &str| TransformArgs {
+            response: HttpResponse {
+                status: 200u32.into(),
+                headers: vec![HttpHeader { name: "Date".to_string(), value: date.to_string() }],
+                body: date.as_bytes().to_vec(),
+            },
+            context: vec![],
+        };
+        let first = monitoring::transform_webhook_response(reply("Mon, 19 Oct 2026 10:00:00 GMT"));
+        let second = monitoring::transform_webhook_response(reply("Mon, 19 Oct 2026 10:00:01 GMT"));
+        assert_eq!(first, second);
+        assert_eq!(first.status, 200u32);
+    }
+
+    #[test]
+    fn evidence_is_capped_per_contract_whatever_the_party_limits() {
+        use crate::limits::Limits;
+
+        let service = setup();
+        let id = funded(&service, args());
+        let unlimited = Limits { max_evidence_bytes_per_dispute: u64::MAX, ..Limits::default() };
+        state::mutate(|s| {
+            s.limit_overrides.insert(payer(), unlimited.clone());
+            s.limit_overrides.insert(payee(), unlimited);
+        });
+        let blob = || ByteBuf::from(vec![0; evidence::MAX_EVIDENCE_BYTES]);
+        let per_contract = evidence::MAX_CONTRACT_EVIDENCE_BYTES as usize / evidence::MAX_EVIDENCE_BYTES;
+        for i in 0..per_contract {
+            let submitter = if i % 2 == 0 { payer() } else { payee() };
+            service.submit_evidence(submitter, id, EvidenceKind::Photo, String::new(), blob()).unwrap();
+        }
+        assert_eq!(
+            service.submit_evidence(payer(), id, EvidenceKind::Photo, "one more".to_string(), ByteBuf::new()),
+            Err(EscrowError::LimitExceeded(LimitKind::EvidenceBytes))
+        );
+
+        let other = funded(&service, args());
+        service.submit_evidence(payer(), other, EvidenceKind::Photo, String::new(), blob()).unwrap();
+    }
+
+    #[test]
+    fn notifications_over_the_quota_replace_the_oldest_read_one() {
+        use crate::limits::Limits;
+
+        let user = principal(7);
+        state::replace(State::default());
+        state::mutate(|s| {
+            s.limit_overrides.insert(user, Limits { max_notifications: 3, ..Limits::default() });
+            for i in 0..3 {
+                notifications::notify(s, user, format!("message {}", i), None, START);
+            }
+            notifications::mark_as_read(s, user, 1);
+            notifications::notify(s, user, "message 3".to_string(), None, START);
+            notifications::notify(s, user, "message 4".to_string(), None, START);
+        });
+        let ids: Vec<u64> = state::read(|s| s.notifications[&user].iter().map(|n| n.id).collect());
+        assert_eq!(ids, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn state_saved_by_the_first_escrow_release_restores() {
+        use crate::hashlock::HashLock;
+        use crate::notifications::Notification;
+        use crate::stable::StableState;
+        use std::collections::BTreeMap;
+
+        // The stable layout as the escrow lifecycle first shipped it.
+        #[derive(CandidType)]
+        struct LedgerConfigV1 {
+            fee: u64,
+        }
+        #[derive(CandidType)]
+        struct EscrowContractV1 {
+            id: u64,
+            payer: Principal,
+            payee: Principal,
+            ledger: Principal,
+            amount: u64,
+            conditions: String,
+            status: ContractStatus,
+            hash_lock: Option<HashLock>,
+            created_at: u64,
+            updated_at: u64,
+            history: Vec<crate::escrow::HistoryEntry>,
+        }
+        #[derive(CandidType)]
+        struct StateV1 {
+            owner: Option<Principal>,
+            ledgers: BTreeMap<Principal, LedgerConfigV1>,
+            escrows: BTreeMap<u64, EscrowContractV1>,
+            next_escrow_id: u64,
+            notifications: BTreeMap<Principal, Vec<Notification>>,
+            next_notification_id: u64,
+        }
+
+        let service = setup();
+        let id = funded(&service, args());
+        let contract = service.contract(id).unwrap();
+        let v1 = StateV1 {
+            owner: Some(principal(3)),
+            ledgers: [(token(), LedgerConfigV1 { fee: FEE })].into(),
+            escrows: [(
+                id,
+                EscrowContractV1 {
+                    id,
+                    payer: payer(),
+                    payee: payee(),
+                    ledger: token(),
+                    amount: AMOUNT,
+                    conditions: contract.conditions.clone(),
+                    status: ContractStatus::Active,
+                    hash_lock: None,
+                    created_at: contract.created_at,
+                    updated_at: contract.updated_at,
+                    history: contract.history.clone(),
+                },
+            )]
+            .into(),
+            next_escrow_id: id + 1,
+            notifications: BTreeMap::new(),
+            next_notification_id: 7,
+        };
+        let bytes = candid::encode_one(v1).unwrap();
+        let restored = candid::decode_one::<StableState>(&bytes).unwrap().into_state();
+        assert_eq!(restored.owner, Some(principal(3)));
+        assert_eq!(restored.ledgers[&token()].decimals, None);
+        assert_eq!(restored.default_limits, crate::limits::Limits::default());
+        let restored_contract = &restored.escrows[&id];
+        assert_eq!(restored_contract.status, ContractStatus::Active);
+        assert!(restored_contract.terms_versions.is_empty());
+        assert_eq!(restored.next_notification_id, 7);
+
+        // What is saved now comes back as it was.
+        let bytes = state::read(|s| candid::encode_one(StableState::of(s))).unwrap();
+        let again = candid::decode_one::<StableState>(&bytes).unwrap().into_state();
+        assert_eq!(again.escrows[&id].terms_versions.len(), 1);
+        assert_eq!(again.ledgers[&token()].decimals, Some(8));
+        assert_eq!(again.next_escrow_id, state::read(|s| s.next_escrow_id));
+    }
+
+    #[test]
+    fn release_approvals_cannot_be_bypassed() {
+        use crate::hashlock::HashLock;
+        use crate::multisig::ReleasePolicyArgs;
+
+        let service = setup();
+        let policy = || Some(ReleasePolicyArgs { approvers: vec![principal(5), principal(6)], threshold: 2 });
+        let lock = HashLockArgs {
+            hash: ByteBuf::from(Sha256::digest(b"secret").to_vec()),
+            expires_at: START + 60 * 1_000_000_000,
+        };
+        for with in [
+            CreateEscrowArgs { hash_lock: Some(lock.clone()), ..args() },
+            CreateEscrowArgs { inspection_window_secs: Some(60), ..args() },
+        ] {
+            let args = CreateEscrowArgs { release_policy: policy(), ..with };
+            assert!(matches!(service.create_escrow(payer(), args), Err(EscrowError::InvalidArgument(_))));
+        }
+
+        // Contracts that combined them before creation refused to.
+        let id = funded(&service, CreateEscrowArgs { release_policy: policy(), ..args() });
+        state::mutate(|s| {
+            let contract = s.escrows.get_mut(&id).unwrap();
+            contract.hash_lock = Some(HashLock::from(lock));
+            contract.inspection_window_secs = Some(60);
+        });
+        block_on(service.approve_release(principal(5), id)).unwrap();
+
+        assert_eq!(
+            block_on(service.confirm_delivery(principal(9), id, ByteBuf::from(b"secret".to_vec()))),
+            Err(EscrowError::ApprovalRequired)
+        );
+        service.clock.advance_secs(60);
+        block_on(service.refund_expired_hash_locks());
+        service.mark_delivered(payee(), id).unwrap();
+        service.clock.advance_secs(60);
+        assert_eq!(block_on(service.release_after_inspection(payee(), id)), Err(EscrowError::ApprovalRequired));
+        assert_eq!(status(id), ContractStatus::Active);
+        assert_eq!(escrow_balance(&service, id), AMOUNT);
+
+        service.dispute_contract(payer(), id, "stuck".to_string()).unwrap();
+        block_on(service.resolve_dispute(principal(3), id, DisputeResolution::ReleaseToPayee)).unwrap();
+        assert_eq!(status(id), ContractStatus::Released);
+    }
+
+    #[test]
+    fn delivery_terms_are_bounded_at_creation() {
+        let service = setup();
+        let rejected = |args: CreateEscrowArgs| {
+            matches!(service.create_escrow(payer(), args), Err(EscrowError::InvalidArgument(_)))
+        };
+        let milestone = |title: &str| Milestone { title: title.to_string(), due_at: None };
+
+        assert!(rejected(CreateEscrowArgs {
+            inspection_window_secs: Some(crate::escrow::MAX_INSPECTION_WINDOW_SECS + 1),
+            ..args()
+        }));
+        assert!(rejected(CreateEscrowArgs { milestones: Some(vec![milestone("step"); 51]), ..args() }));
+        assert!(rejected(CreateEscrowArgs { milestones: Some(vec![milestone("  ")]), ..args() }));
+        assert!(rejected(CreateEscrowArgs { milestones: Some(vec![milestone(&"x".repeat(201))]), ..args() }));
+        assert!(rejected(CreateEscrowArgs {
+            milestones: Some(vec![Milestone { title: "late".to_string(), due_at: Some(START) }]),
+            ..args()
+        }));
+        assert!(rejected(CreateEscrowArgs {
+            required_evidence: Some(vec![EvidenceKind::Photo, EvidenceKind::Photo]),
+            ..args()
+        }));
+        service
+            .create_escrow(payer(), CreateEscrowArgs { milestones: Some(vec![milestone("step"); 50]), ..args() })
+            .unwrap();
+    }
+
+    #[test]
+    fn a_huge_inspection_window_never_elapses() {
+        let service = setup();
+        let id = funded(&service, CreateEscrowArgs { inspection_window_secs: Some(60), ..args() });
+        service.mark_delivered(payee(), id).unwrap();
+        state::mutate(|s| s.escrows.get_mut(&id).unwrap().inspection_window_secs = Some(u64::MAX));
+
+        service.clock.advance_secs(3_600);
+        assert!(!state::read(|s| s.escrows[&id].inspection_elapsed(service.clock.now())));
+        assert!(matches!(block_on(service.release_after_inspection(payee(), id)), Err(EscrowError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn recurring_escrow_lapses_after_missed_periods_in_a_row() {
+        let service = setup();
+        let id = service.create_subscription(payer(), subscription_args()).unwrap();
+        service.accept_subscription(payee(), id).unwrap();
+        let subscription = || state::read(|s| s.subscriptions[&id].clone());
+        let next_period = || {
+            block_on(service.open_due_subscription_periods());
+            service.clock.advance_secs(3_600);
+        };
+
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        next_period();
+        next_period();
+        assert_eq!((subscription().periods.len(), subscription().missed_periods), (1, 1));
+
+        service.ledger.approve(payer(), AMOUNT + FEE).unwrap();
+        next_period();
+        assert_eq!((subscription().periods.len(), subscription().missed_periods), (2, 0));
+
+        for _ in 1..subscription::MAX_MISSED_PERIODS {
+            next_period();
+        }
+        assert_eq!(subscription().status, SubscriptionStatus::Active);
+        next_period();
+        assert_eq!(subscription().status, SubscriptionStatus::Lapsed);
+        assert_eq!(subscription().next_due, None);
+        next_period();
+        assert_eq!(subscription().missed_periods, subscription::MAX_MISSED_PERIODS);
+    }
+
+    #[test]
+    fn recurring_periods_need_current_kyc() {
+        use crate::kyc::{self, KycLevel, KycThreshold};
+
+        let service = setup();
+        let issuer = principal(5);
+        let now = service.clock.now();
+        let hour = 3_600 * 1_000_000_000;
+        state::mutate(|s| {
+            s.kyc_thresholds = vec![KycThreshold { ledger: token(), min_amount: AMOUNT, level: KycLevel::Basic }];
+            s.kyc_issuers.insert(issuer);
+            kyc::attest(s, issuer, payer(), KycLevel::Basic, now + hour, now).unwrap();
+            kyc::attest(s, issuer, payee(), KycLevel::Basic, now + 2 * hour, now).unwrap();
+        });
+        let id = service.create_subscription(payer(), subscription_args()).unwrap();
+        service.accept_subscription(payee(), id).unwrap();
+        service.ledger.approve(payer(), 2 * (AMOUNT + FEE)).unwrap();
+
+        block_on(service.open_due_subscription_periods());
+        let balance = service.ledger.balance(Account::from(payer()));
+        service.clock.advance_secs(3_600);
+        block_on(service.open_due_subscription_periods());
+
+        let subscription = state::read(|s| s.subscriptions[&id].clone());
+        assert_eq!((subscription.periods.len(), subscription.missed_periods), (1, 1));
+        assert!(subscription.last_error.is_some_and(|e| e.contains("KycRequired")));
+        assert_eq!(service.ledger.balance(Account::from(payer())), balance);
+    }
+
+    #[test]
+    fn unversioned_contracts_record_their_original_terms_on_first_amendment() {
+        let service = setup();
+        let id = funded(&service, args());
+        let original = state::mutate(|s| std::mem::take(&mut s.escrows.get_mut(&id).unwrap().terms_versions));
+        let contract = state::read(|s| s.escrows[&id].clone());
+        assert_eq!(amendment::in_force_at(&contract, START).unwrap().unwrap().hash, original[0].hash);
+
+        let milestone = AmendmentChange::AddMilestone(Milestone { title: "Draft".to_string(), due_at: None });
+        service.propose_amendment(payee(), id, milestone.clone()).unwrap();
+        service.clock.advance_secs(60);
+        let version = block_on(service.approve_amendment(payer(), id, milestone)).unwrap();
+        assert_eq!(version.version, 2);
+
+        let versions = state::read(|s| s.escrows[&id].terms_versions.clone());
+        assert_eq!(versions.len(), 2);
+        assert_eq!((versions[0].hash.clone(), versions[0].in_force_from), (original[0].hash.clone(), START));
+        assert_eq!(versions[1].terms.milestones.len(), 1);
+    }
+}
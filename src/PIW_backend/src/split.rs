@@ -0,0 +1,106 @@
+//! Escrows that pay several recipients on release, e.g. a contractor and
+//! their subcontractors. Shares are fixed up front, either as exact amounts
+//! or as basis points of the escrow amount.
+
+use crate::error::{EscrowError, EscrowResult};
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeSet;
+
+const TOTAL_BASIS_POINTS: u64 = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Share {
+    Amount(u64),
+    BasisPoints(u32),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PayeeShare {
+    pub recipient: Principal,
+    pub share: Share,
+}
+
+/// A recipient's resolved cut of the escrow. `block_index` is set once their
+/// transfer has gone through, so a retried release only pays the rest.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SplitPayee {
+    pub recipient: Principal,
+    pub amount: u64,
+    pub block_index: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SplitTransfer {
+    pub recipient: Principal,
+    pub amount: u64,
+    pub result: Result<u64, String>,
+}
+
+/// Turns the requested shares into amounts that add up to exactly `amount`.
+/// Rounding dust from basis-point shares goes to the last recipient.
+pub fn allocate(amount: u64, shares: &[PayeeShare], fee: u64) -> EscrowResult<Vec<SplitPayee>> {
+    if shares.is_empty() {
+        return Err(EscrowError::InvalidArgument("split needs at least one recipient".to_string()));
+    }
+    let recipients: BTreeSet<_> = shares.iter().map(|s| s.recipient).collect();
+    if recipients.len() != shares.len() {
+        return Err(EscrowError::InvalidArgument("split recipients must be unique".to_string()));
+    }
+
+    let fixed: Vec<u64> = shares
+        .iter()
+        .filter_map(|s| match s.share {
+            Share::Amount(a) => Some(a),
+            Share::BasisPoints(_) => None,
+        })
+        .collect();
+    let points: Vec<u64> = shares
+        .iter()
+        .filter_map(|s| match s.share {
+            Share::BasisPoints(bp) => Some(bp as u64),
+            Share::Amount(_) => None,
+        })
+        .collect();
+
+    let amounts = if fixed.len() == shares.len() {
+        if fixed.iter().try_fold(0u64, |acc, a| acc.checked_add(*a)) != Some(amount) {
+            return Err(EscrowError::InvalidArgument(
+                "split amounts must add up to the escrow amount".to_string(),
+            ));
+        }
+        fixed
+    } else if points.len() == shares.len() {
+        if points.iter().sum::<u64>() != TOTAL_BASIS_POINTS {
+            return Err(EscrowError::InvalidArgument(
+                "split basis points must add up to 10000".to_string(),
+            ));
+        }
+        let mut amounts: Vec<u64> = points
+            .iter()
+            .map(|bp| (amount as u128 * *bp as u128 / TOTAL_BASIS_POINTS as u128) as u64)
+            .collect();
+        let dust = amount - amounts.iter().sum::<u64>();
+        *amounts.last_mut().expect("shares is not empty") += dust;
+        amounts
+    } else {
+        return Err(EscrowError::InvalidArgument(
+            "split shares must be all amounts or all basis points".to_string(),
+        ));
+    };
+
+    if amounts.iter().any(|a| *a <= fee) {
+        return Err(EscrowError::InvalidArgument(format!(
+            "every split share must exceed the ledger fee of {}",
+            fee
+        )));
+    }
+    Ok(shares
+        .iter()
+        .zip(amounts)
+        .map(|(s, amount)| SplitPayee {
+            recipient: s.recipient,
+            amount,
+            block_index: None,
+        })
+        .collect())
+}
@@ -0,0 +1,253 @@
+//! The layout of the state in stable memory. The state is saved as a Candid
+//! record on upgrade, and Candid only accepts saved data that lacks a field
+//! if the field's type is `opt`; `#[serde(default)]` is never consulted. So
+//! every field added after the escrow lifecycle first shipped is an `Option`
+//! here, filled in with its default on restore, and fields added from now on
+//! must follow suit. Saving borrows from the live state, so an upgrade does
+//! not need a second copy of it.
+
+use crate::access::Role;
+use crate::amendment::{Amendment, TermsVersion};
+use crate::cancellation::Cancellation;
+use crate::escrow::{ContractStatus, EscrowContract, HistoryEntry, Milestone};
+use crate::evidence::{Evidence, EvidenceKind};
+use crate::export::ExportLink;
+use crate::hashlock::HashLock;
+use crate::idempotency::CachedResult;
+use crate::kyc::{KycAttestation, KycThreshold};
+use crate::limits::Limits;
+use crate::log::LogEntry;
+use crate::monitoring::{CallCost, CyclesAlertConfig, CyclesSample};
+use crate::multisig::ReleaseApprovals;
+use crate::notifications::Notification;
+use crate::pause::{PauseInfo, PauseScope};
+use crate::reputation::Rating;
+use crate::reserves::ReserveReport;
+use crate::split::SplitPayee;
+use crate::state::{LedgerConfig, State};
+use crate::subscription::Subscription;
+use crate::template::{EscrowTemplate, Industry};
+use crate::transfers::PendingTransfer;
+use crate::vendor::Vendor;
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+type Field<'a, T> = Option<Cow<'a, T>>;
+
+fn saved<T: Clone>(value: &T) -> Field<'_, T> {
+    Some(Cow::Borrowed(value))
+}
+
+fn restored<T: Clone + Default>(field: Field<'_, T>) -> T {
+    field.map(Cow::into_owned).unwrap_or_default()
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableState<'a> {
+    owner: Option<Principal>,
+    ledgers: Cow<'a, BTreeMap<Principal, LedgerConfig>>,
+    escrows: BTreeMap<u64, StableEscrow<'a>>,
+    next_escrow_id: u64,
+    notifications: Cow<'a, BTreeMap<Principal, Vec<Notification>>>,
+    next_notification_id: u64,
+    subscriptions: Field<'a, BTreeMap<u64, Subscription>>,
+    next_subscription_id: Option<u64>,
+    platform_fee_bps: Option<u32>,
+    templates: Field<'a, BTreeMap<Industry, EscrowTemplate>>,
+    roles: Field<'a, BTreeMap<Principal, BTreeSet<Role>>>,
+    paused: Field<'a, BTreeMap<PauseScope, PauseInfo>>,
+    default_limits: Field<'a, Limits>,
+    limit_overrides: Field<'a, BTreeMap<Principal, Limits>>,
+    recent_creations: Field<'a, BTreeMap<Principal, Vec<u64>>>,
+    cycles_alert: Field<'a, CyclesAlertConfig>,
+    low_cycles_alerted: Option<bool>,
+    cycles_samples: Field<'a, Vec<CyclesSample>>,
+    call_costs: Field<'a, Vec<CallCost>>,
+    logs: Field<'a, VecDeque<LogEntry>>,
+    next_log_id: Option<u64>,
+    export_links: Field<'a, BTreeMap<String, ExportLink>>,
+    ratings: Field<'a, BTreeMap<u64, Vec<Rating>>>,
+    vendors: Field<'a, BTreeMap<String, Vendor>>,
+    kyc_issuers: Field<'a, BTreeSet<Principal>>,
+    kyc: Field<'a, BTreeMap<Principal, KycAttestation>>,
+    kyc_thresholds: Field<'a, Vec<KycThreshold>>,
+    reserve_deficit_thresholds: Field<'a, BTreeMap<Principal, u64>>,
+    reserve_report: Field<'a, ReserveReport>,
+    pending_transfers: Field<'a, BTreeMap<u64, PendingTransfer>>,
+    next_transfer_id: Option<u64>,
+    idempotency: Field<'a, BTreeMap<(Principal, String), CachedResult>>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StableEscrow<'a> {
+    id: u64,
+    payer: Principal,
+    payee: Principal,
+    ledger: Principal,
+    amount: u64,
+    conditions: Cow<'a, str>,
+    status: ContractStatus,
+    hash_lock: Cow<'a, Option<HashLock>>,
+    release_approvals: Field<'a, ReleaseApprovals>,
+    splits: Field<'a, Vec<SplitPayee>>,
+    subscription_id: Option<u64>,
+    industry: Option<Industry>,
+    fee_bps: Option<u32>,
+    inspection_window_secs: Option<u64>,
+    milestones: Field<'a, Vec<Milestone>>,
+    required_evidence: Field<'a, Vec<EvidenceKind>>,
+    evidence: Field<'a, Vec<Evidence>>,
+    delivered_at: Option<u64>,
+    vendor_id: Field<'a, String>,
+    payee_account: Option<Account>,
+    cancellation: Field<'a, Cancellation>,
+    amendment: Field<'a, Amendment>,
+    terms_versions: Field<'a, Vec<TermsVersion>>,
+    created_at: u64,
+    updated_at: u64,
+    history: Cow<'a, [HistoryEntry]>,
+}
+
+impl<'a> StableState<'a> {
+    pub fn of(s: &'a State) -> Self {
+        StableState {
+            owner: s.owner,
+            ledgers: Cow::Borrowed(&s.ledgers),
+            escrows: s.escrows.iter().map(|(id, c)| (*id, StableEscrow::of(c))).collect(),
+            next_escrow_id: s.next_escrow_id,
+            notifications: Cow::Borrowed(&s.notifications),
+            next_notification_id: s.next_notification_id,
+            subscriptions: saved(&s.subscriptions),
+            next_subscription_id: Some(s.next_subscription_id),
+            platform_fee_bps: Some(s.platform_fee_bps),
+            templates: saved(&s.templates),
+            roles: saved(&s.roles),
+            paused: saved(&s.paused),
+            default_limits: saved(&s.default_limits),
+            limit_overrides: saved(&s.limit_overrides),
+            recent_creations: saved(&s.recent_creations),
+            cycles_alert: saved(&s.cycles_alert),
+            low_cycles_alerted: Some(s.low_cycles_alerted),
+            cycles_samples: saved(&s.cycles_samples),
+            call_costs: saved(&s.call_costs),
+            logs: saved(&s.logs),
+            next_log_id: Some(s.next_log_id),
+            export_links: saved(&s.export_links),
+            ratings: saved(&s.ratings),
+            vendors: saved(&s.vendors),
+            kyc_issuers: saved(&s.kyc_issuers),
+            kyc: saved(&s.kyc),
+            kyc_thresholds: saved(&s.kyc_thresholds),
+            reserve_deficit_thresholds: saved(&s.reserve_deficit_thresholds),
+            reserve_report: s.reserve_report.as_ref().map(Cow::Borrowed),
+            pending_transfers: saved(&s.pending_transfers),
+            next_transfer_id: Some(s.next_transfer_id),
+            idempotency: saved(&s.idempotency),
+        }
+    }
+
+    pub fn into_state(self) -> State {
+        State {
+            owner: self.owner,
+            ledgers: self.ledgers.into_owned(),
+            escrows: self.escrows.into_iter().map(|(id, c)| (id, c.into_contract())).collect(),
+            next_escrow_id: self.next_escrow_id,
+            notifications: self.notifications.into_owned(),
+            next_notification_id: self.next_notification_id,
+            subscriptions: restored(self.subscriptions),
+            next_subscription_id: self.next_subscription_id.unwrap_or_default(),
+            platform_fee_bps: self.platform_fee_bps.unwrap_or_default(),
+            templates: restored(self.templates),
+            roles: restored(self.roles),
+            paused: restored(self.paused),
+            default_limits: restored(self.default_limits),
+            limit_overrides: restored(self.limit_overrides),
+            recent_creations: restored(self.recent_creations),
+            cycles_alert: restored(self.cycles_alert),
+            low_cycles_alerted: self.low_cycles_alerted.unwrap_or_default(),
+            cycles_samples: restored(self.cycles_samples),
+            call_costs: restored(self.call_costs),
+            logs: restored(self.logs),
+            next_log_id: self.next_log_id.unwrap_or_default(),
+            export_links: restored(self.export_links),
+            ratings: restored(self.ratings),
+            vendors: restored(self.vendors),
+            kyc_issuers: restored(self.kyc_issuers),
+            kyc: restored(self.kyc),
+            kyc_thresholds: restored(self.kyc_thresholds),
+            reserve_deficit_thresholds: restored(self.reserve_deficit_thresholds),
+            reserve_report: self.reserve_report.map(Cow::into_owned),
+            pending_transfers: restored(self.pending_transfers),
+            next_transfer_id: self.next_transfer_id.unwrap_or_default(),
+            idempotency: restored(self.idempotency),
+        }
+    }
+}
+
+impl<'a> StableEscrow<'a> {
+    fn of(c: &'a EscrowContract) -> Self {
+        StableEscrow {
+            id: c.id,
+            payer: c.payer,
+            payee: c.payee,
+            ledger: c.ledger,
+            amount: c.amount,
+            conditions: Cow::Borrowed(c.conditions.as_str()),
+            status: c.status,
+            hash_lock: Cow::Borrowed(&c.hash_lock),
+            release_approvals: c.release_approvals.as_ref().map(Cow::Borrowed),
+            splits: c.splits.as_ref().map(Cow::Borrowed),
+            subscription_id: c.subscription_id,
+            industry: c.industry,
+            fee_bps: Some(c.fee_bps),
+            inspection_window_secs: c.inspection_window_secs,
+            milestones: saved(&c.milestones),
+            required_evidence: saved(&c.required_evidence),
+            evidence: saved(&c.evidence),
+            delivered_at: c.delivered_at,
+            vendor_id: c.vendor_id.as_ref().map(Cow::Borrowed),
+            payee_account: c.payee_account,
+            cancellation: c.cancellation.as_ref().map(Cow::Borrowed),
+            amendment: c.amendment.as_ref().map(Cow::Borrowed),
+            terms_versions: saved(&c.terms_versions),
+            created_at: c.created_at,
+            updated_at: c.updated_at,
+            history: Cow::Borrowed(c.history.as_slice()),
+        }
+    }
+
+    /// Contracts saved before terms were versioned come back without any;
+    /// `amendment::apply` records their original terms on first use.
+    fn into_contract(self) -> EscrowContract {
+        EscrowContract {
+            id: self.id,
+            payer: self.payer,
+            payee: self.payee,
+            ledger: self.ledger,
+            amount: self.amount,
+            conditions: self.conditions.into_owned(),
+            status: self.status,
+            hash_lock: self.hash_lock.into_owned(),
+            release_approvals: self.release_approvals.map(Cow::into_owned),
+            splits: self.splits.map(Cow::into_owned),
+            subscription_id: self.subscription_id,
+            industry: self.industry,
+            fee_bps: self.fee_bps.unwrap_or_default(),
+            inspection_window_secs: self.inspection_window_secs,
+            milestones: restored(self.milestones),
+            required_evidence: restored(self.required_evidence),
+            evidence: restored(self.evidence),
+            delivered_at: self.delivered_at,
+            vendor_id: self.vendor_id.map(Cow::into_owned),
+            payee_account: self.payee_account,
+            cancellation: self.cancellation.map(Cow::into_owned),
+            amendment: self.amendment.map(Cow::into_owned),
+            terms_versions: restored(self.terms_versions),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            history: self.history.into_owned(),
+        }
+    }
+}
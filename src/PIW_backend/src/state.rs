@@ -0,0 +1,98 @@
+use crate::access::Role;
+use crate::escrow::EscrowContract;
+use crate::export::ExportLink;
+use crate::idempotency::CachedResult;
+use crate::limits::Limits;
+use crate::kyc::{KycAttestation, KycThreshold};
+use crate::log::LogEntry;
+use crate::monitoring::{CallCost, CyclesAlertConfig, CyclesSample};
+use crate::notifications::Notification;
+use crate::pause::{PauseInfo, PauseScope};
+use crate::reputation::Rating;
+use crate::reserves::ReserveReport;
+use crate::subscription::Subscription;
+use crate::template::{EscrowTemplate, Industry};
+use crate::transfers::PendingTransfer;
+use crate::vendor::Vendor;
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Per-ledger settings the canister needs to move funds on that ledger.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerConfig {
+    pub fee: u64,
+    /// `None` for ledgers registered before decimals were recorded; those
+    /// are all 8-decimal ledgers.
+    pub decimals: Option<u8>,
+}
+
+/// Everything the canister keeps across calls. Saved to stable memory on
+/// upgrade through `stable::StableState`, which has to learn about every
+/// field added here; see there for how.
+#[derive(Default)]
+pub struct State {
+    pub owner: Option<Principal>,
+    pub ledgers: BTreeMap<Principal, LedgerConfig>,
+    pub escrows: BTreeMap<u64, EscrowContract>,
+    pub next_escrow_id: u64,
+    pub notifications: BTreeMap<Principal, Vec<Notification>>,
+    pub next_notification_id: u64,
+    pub subscriptions: BTreeMap<u64, Subscription>,
+    pub next_subscription_id: u64,
+    pub platform_fee_bps: u32,
+    pub templates: BTreeMap<Industry, EscrowTemplate>,
+    pub roles: BTreeMap<Principal, BTreeSet<Role>>,
+    pub paused: BTreeMap<PauseScope, PauseInfo>,
+    pub default_limits: Limits,
+    pub limit_overrides: BTreeMap<Principal, Limits>,
+    pub recent_creations: BTreeMap<Principal, Vec<u64>>,
+    pub cycles_alert: CyclesAlertConfig,
+    pub low_cycles_alerted: bool,
+    pub cycles_samples: Vec<CyclesSample>,
+    pub call_costs: Vec<CallCost>,
+    pub logs: VecDeque<LogEntry>,
+    pub next_log_id: u64,
+    pub export_links: BTreeMap<String, ExportLink>,
+    /// Keyed by contract id; at most one rating per party.
+    pub ratings: BTreeMap<u64, Vec<Rating>>,
+    /// Keyed by handle.
+    pub vendors: BTreeMap<String, Vendor>,
+    pub kyc_issuers: BTreeSet<Principal>,
+    pub kyc: BTreeMap<Principal, KycAttestation>,
+    pub kyc_thresholds: Vec<KycThreshold>,
+    /// Per ledger; a deficit above it pauses payouts. Ledgers not listed
+    /// tolerate no deficit at all.
+    pub reserve_deficit_thresholds: BTreeMap<Principal, u64>,
+    pub reserve_report: Option<ReserveReport>,
+    pub pending_transfers: BTreeMap<u64, PendingTransfer>,
+    pub next_transfer_id: u64,
+    pub idempotency: BTreeMap<(Principal, String), CachedResult>,
+}
+
+impl State {
+    /// Hands out the next contract id. Id 0 is never used: its subaccount
+    /// would be all zeros, i.e. the canister's default account, which is where
+    /// platform fees are collected.
+    pub fn allocate_escrow_id(&mut self) -> u64 {
+        let id = self.next_escrow_id.max(1);
+        self.next_escrow_id = id + 1;
+        id
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::default();
+}
+
+pub fn read<R>(f: impl FnOnce(&State) -> R) -> R {
+    STATE.with(|s| f(&s.borrow()))
+}
+
+pub fn mutate<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|s| f(&mut s.borrow_mut()))
+}
+
+pub fn replace(state: State) {
+    STATE.with(|s| *s.borrow_mut() = state);
+}
@@ -0,0 +1,140 @@
+//! Recurring escrows for retainer-style arrangements. A subscription is a
+//! template agreed once by both parties; on every due date the canister opens
+//! a fresh escrow for the period and funds it from the payer's ICRC-2
+//! allowance. Each period escrow is then released or disputed on its own.
+
+use crate::error::{EscrowError, EscrowResult};
+use candid::{CandidType, Deserialize, Principal};
+
+pub const MIN_INTERVAL_SECS: u64 = 60 * 60;
+/// Periods in a row that may go unfunded before the series lapses, so a
+/// payer who revoked the allowance is not pulled from forever.
+pub const MAX_MISSED_PERIODS: u32 = 3;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    Pending,
+    Active,
+    Completed,
+    Cancelled,
+    /// Stopped after `MAX_MISSED_PERIODS` periods in a row went unfunded.
+    Lapsed,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CreateSubscriptionArgs {
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+    pub interval_secs: u64,
+    pub max_periods: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    pub id: u64,
+    pub payer: Principal,
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+    pub interval_secs: u64,
+    pub max_periods: Option<u32>,
+    pub status: SubscriptionStatus,
+    pub next_due: Option<u64>,
+    pub periods_claimed: u32,
+    pub periods: Vec<u64>,
+    /// Periods missed in a row since the last funded one.
+    pub missed_periods: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl CreateSubscriptionArgs {
+    pub fn validate(&self) -> EscrowResult<()> {
+        if self.interval_secs < MIN_INTERVAL_SECS {
+            return Err(EscrowError::InvalidArgument(format!(
+                "interval must be at least {} seconds",
+                MIN_INTERVAL_SECS
+            )));
+        }
+        if self.max_periods == Some(0) {
+            return Err(EscrowError::InvalidArgument("max_periods must be positive".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Subscription {
+    pub fn new(id: u64, payer: Principal, args: CreateSubscriptionArgs, now: u64) -> Self {
+        Subscription {
+            id,
+            payer,
+            payee: args.payee,
+            ledger: args.ledger,
+            amount: args.amount,
+            conditions: args.conditions,
+            interval_secs: args.interval_secs,
+            max_periods: args.max_periods,
+            status: SubscriptionStatus::Pending,
+            next_due: None,
+            periods_claimed: 0,
+            periods: Vec::new(),
+            missed_periods: 0,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn is_due(&self, now: u64) -> bool {
+        self.status == SubscriptionStatus::Active && self.next_due.is_some_and(|due| due <= now)
+    }
+
+    /// Claims the current period and moves the due date on, so an overlapping
+    /// sweep cannot open the same period twice. Returns the period number.
+    pub fn claim_period(&mut self, now: u64) -> u32 {
+        self.periods_claimed += 1;
+        let period = self.periods_claimed;
+        let due = self.next_due.unwrap_or(now);
+        self.next_due = Some(due + self.interval_secs * NANOS_PER_SEC);
+        if self.max_periods.is_some_and(|max| period >= max) {
+            self.status = SubscriptionStatus::Completed;
+            self.next_due = None;
+        }
+        self.updated_at = now;
+        period
+    }
+
+    pub fn record_funded(&mut self, escrow_id: u64, now: u64) {
+        self.periods.push(escrow_id);
+        self.missed_periods = 0;
+        self.last_error = None;
+        self.updated_at = now;
+    }
+
+    /// Returns whether this miss made the series lapse.
+    pub fn record_missed(&mut self, error: String, now: u64) -> bool {
+        self.missed_periods += 1;
+        self.last_error = Some(error);
+        self.updated_at = now;
+        let lapsed = self.status == SubscriptionStatus::Active && self.missed_periods >= MAX_MISSED_PERIODS;
+        if lapsed {
+            self.status = SubscriptionStatus::Lapsed;
+            self.next_due = None;
+        }
+        lapsed
+    }
+}
+
+/// The same for every attempt at a period, so the ledger can tell a repeated
+/// pull from a new one.
+pub fn period_memo(subscription_id: u64, period: u32) -> Vec<u8> {
+    let mut memo = b"PIW period ".to_vec();
+    memo.extend_from_slice(&subscription_id.to_be_bytes());
+    memo.extend_from_slice(&period.to_be_bytes());
+    memo
+}
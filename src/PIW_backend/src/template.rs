@@ -0,0 +1,72 @@
+//! Owner-managed presets per industry. `create_escrow_from_template` copies
+//! the template's terms into any field the caller left empty.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::escrow::{CreateEscrowArgs, Milestone, MAX_INSPECTION_WINDOW_SECS};
+use crate::evidence::EvidenceKind;
+use candid::{CandidType, Deserialize};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Industry {
+    RealEstate,
+    Freelance,
+    Vehicles,
+    DomainNames,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MilestoneTemplate {
+    pub title: String,
+    pub due_after_secs: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EscrowTemplate {
+    pub industry: Industry,
+    pub inspection_window_secs: Option<u64>,
+    pub milestones: Vec<MilestoneTemplate>,
+    pub required_evidence: Vec<EvidenceKind>,
+    pub fee_bps: Option<u32>,
+}
+
+impl EscrowTemplate {
+    pub fn validate(&self) -> EscrowResult<()> {
+        if self.fee_bps.is_some_and(|bps| bps > 10_000) {
+            return Err(EscrowError::InvalidArgument("fee_bps may not exceed 10000".to_string()));
+        }
+        if self.inspection_window_secs.is_some_and(|secs| secs > MAX_INSPECTION_WINDOW_SECS) {
+            return Err(EscrowError::InvalidArgument(format!(
+                "inspection window may be at most {} seconds",
+                MAX_INSPECTION_WINDOW_SECS
+            )));
+        }
+        // The milestones themselves are checked like any others when an
+        // escrow is created from the template.
+        if self.milestones.iter().any(|m| m.due_after_secs == Some(0)) {
+            return Err(EscrowError::InvalidArgument("milestones must be due after creation".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, args: &mut CreateEscrowArgs, now: u64) {
+        if args.inspection_window_secs.is_none() {
+            args.inspection_window_secs = self.inspection_window_secs;
+        }
+        if args.milestones.is_none() {
+            args.milestones = Some(
+                self.milestones
+                    .iter()
+                    .map(|m| Milestone {
+                        title: m.title.clone(),
+                        due_at: m.due_after_secs.map(|secs| now.saturating_add(secs.saturating_mul(NANOS_PER_SEC))),
+                    })
+                    .collect(),
+            );
+        }
+        if args.required_evidence.is_none() {
+            args.required_evidence = Some(self.required_evidence.clone());
+        }
+    }
+}
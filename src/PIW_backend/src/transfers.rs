@@ -0,0 +1,128 @@
+//! Queue of payouts out of escrow subaccounts. A payout is stored before it
+//! is sent and only removed once the ledger confirms it, so a call that fails
+//! half way, e.g. because the reply was lost, cannot leave a contract marked
+//! paid without the money having moved or the other way round. Every attempt
+//! of a queued transfer carries the same memo and `created_at_time`, which
+//! makes the ledger answer a repeat with `Duplicate` instead of paying twice.
+
+use crate::error::EscrowError;
+use crate::escrow::EscrowEvent;
+use crate::state::State;
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const RETRY_BASE_SECS: u64 = 30;
+const MAX_BACKOFF_SECS: u64 = 60 * 60;
+/// How long after queueing a transfer is retried. The ledger deduplicates
+/// for 24 hours after `created_at_time`; stopping an hour short of that
+/// leaves room for clock drift, so every attempt is still answered with
+/// `Duplicate` if an earlier one went through.
+const RETRY_WINDOW_SECS: u64 = 23 * 60 * 60;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferKind {
+    Release,
+    Refund,
+    SplitShare { recipient: Principal },
+    /// The payee's share under a confirmed cancellation.
+    CancellationShare,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingTransfer {
+    pub id: u64,
+    pub contract_id: u64,
+    pub kind: TransferKind,
+    pub ledger: Principal,
+    pub to: Account,
+    /// Net of the ledger fee, fixed at queueing so every attempt is the same
+    /// transfer.
+    pub amount: u64,
+    pub created_at_time: u64,
+    /// Who asked for the payout, and the history event that goes with it.
+    pub actor: Principal,
+    pub event: Option<EscrowEvent>,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+}
+
+/// Unique per transfer, so two payouts that happen to have the same amount
+/// and recipient are never taken for duplicates of each other.
+pub fn memo(transfer_id: u64) -> Vec<u8> {
+    let mut memo = b"PIW payout ".to_vec();
+    memo.extend_from_slice(&transfer_id.to_be_bytes());
+    memo
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn enqueue(
+    state: &mut State,
+    contract_id: u64,
+    kind: TransferKind,
+    ledger: Principal,
+    to: Account,
+    amount: u64,
+    actor: Principal,
+    event: Option<EscrowEvent>,
+    now: u64,
+) -> u64 {
+    let id = state.next_transfer_id;
+    state.next_transfer_id += 1;
+    state.pending_transfers.insert(
+        id,
+        PendingTransfer {
+            id,
+            contract_id,
+            kind,
+            ledger,
+            to,
+            amount,
+            created_at_time: now,
+            actor,
+            event,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+        },
+    );
+    id
+}
+
+pub fn pending_for(state: &State, contract_id: u64) -> Vec<&PendingTransfer> {
+    state
+        .pending_transfers
+        .values()
+        .filter(|t| t.contract_id == contract_id)
+        .collect()
+}
+
+pub fn due(state: &State, now: u64) -> Vec<PendingTransfer> {
+    state
+        .pending_transfers
+        .values()
+        .filter(|t| t.next_attempt_at <= now)
+        .cloned()
+        .collect()
+}
+
+fn backoff_secs(attempts: u32) -> u64 {
+    (RETRY_BASE_SECS << attempts.saturating_sub(1).min(16)).min(MAX_BACKOFF_SECS)
+}
+
+/// Schedules the next attempt after a failed one. A transfer is only given up
+/// once the next attempt would fall outside the retry window, as a repeat
+/// past the ledger's deduplication window could pay twice. It is then dropped
+/// from the queue and returned, leaving the contract as it was so the payout
+/// can be asked for again.
+pub fn record_failure(state: &mut State, transfer_id: u64, error: &EscrowError, now: u64) -> Option<PendingTransfer> {
+    let transfer = state.pending_transfers.get_mut(&transfer_id)?;
+    transfer.attempts += 1;
+    transfer.last_error = Some(format!("{:?}", error));
+    transfer.next_attempt_at = now + backoff_secs(transfer.attempts) * NANOS_PER_SEC;
+    if transfer.next_attempt_at > transfer.created_at_time + RETRY_WINDOW_SECS * NANOS_PER_SEC {
+        return state.pending_transfers.remove(&transfer_id);
+    }
+    None
+}
@@ -0,0 +1,147 @@
+//! Vendor directory. A vendor is a payee with a public profile under a unique
+//! handle, which is what buyers type as the vendor id when opening an escrow.
+//! Admins vouch for a vendor with the verification badge and can suspend one.
+
+use crate::error::{EscrowError, EscrowResult};
+use crate::state::State;
+use crate::template::Industry;
+use candid::{CandidType, Deserialize, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+
+const MIN_HANDLE_CHARS: usize = 3;
+const MAX_HANDLE_CHARS: usize = 32;
+const MAX_BUSINESS_NAME_CHARS: usize = 100;
+const MAX_PAYOUT_ACCOUNTS: usize = 10;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VendorStatus {
+    Active,
+    /// Switched off by the vendor; they can switch it back on.
+    Inactive,
+    /// Switched off by an admin; only an admin can lift it.
+    Suspended,
+}
+
+/// Where the vendor wants to be paid on one ledger, instead of the default
+/// account of their principal.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PayoutAccount {
+    pub ledger: Principal,
+    pub account: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Vendor {
+    pub handle: String,
+    pub principal: Principal,
+    pub business_name: String,
+    pub payout_accounts: Vec<PayoutAccount>,
+    pub categories: Vec<Industry>,
+    pub verified: bool,
+    pub status: VendorStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VendorArgs {
+    pub handle: String,
+    pub business_name: String,
+    pub payout_accounts: Vec<PayoutAccount>,
+    pub categories: Vec<Industry>,
+}
+
+impl VendorArgs {
+    /// `canister_id` may not receive payouts: its default account collects
+    /// fees and its subaccounts hold other escrows.
+    pub fn validate(&self, canister_id: Principal) -> EscrowResult<()> {
+        let handle_ok = (MIN_HANDLE_CHARS..=MAX_HANDLE_CHARS).contains(&self.handle.len())
+            && self
+                .handle
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !handle_ok {
+            return Err(EscrowError::InvalidArgument(format!(
+                "handle must be {} to {} lowercase letters, digits, '-' or '_'",
+                MIN_HANDLE_CHARS, MAX_HANDLE_CHARS
+            )));
+        }
+        let name_len = self.business_name.trim().chars().count();
+        if name_len == 0 || name_len > MAX_BUSINESS_NAME_CHARS {
+            return Err(EscrowError::InvalidArgument(format!(
+                "business_name must be 1 to {} characters",
+                MAX_BUSINESS_NAME_CHARS
+            )));
+        }
+        if self.payout_accounts.len() > MAX_PAYOUT_ACCOUNTS {
+            return Err(EscrowError::InvalidArgument(format!(
+                "at most {} payout accounts are allowed",
+                MAX_PAYOUT_ACCOUNTS
+            )));
+        }
+        for (i, payout) in self.payout_accounts.iter().enumerate() {
+            if self.payout_accounts[..i].iter().any(|p| p.ledger == payout.ledger) {
+                return Err(EscrowError::InvalidArgument(format!(
+                    "more than one payout account for ledger {}",
+                    payout.ledger
+                )));
+            }
+            if payout.account.owner == canister_id || payout.account.owner == Principal::anonymous() {
+                return Err(EscrowError::InvalidArgument(format!(
+                    "payout account owner {} is not allowed",
+                    payout.account.owner
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn by_principal(state: &State, principal: Principal) -> Option<&Vendor> {
+    state.vendors.values().find(|v| v.principal == principal)
+}
+
+/// Checks that `handle` names an active vendor who is `payee`, and returns
+/// where that vendor wants to be paid on `ledger`, if not the default account.
+pub fn payout_for(state: &State, handle: &str, payee: Principal, ledger: Principal) -> EscrowResult<Option<Account>> {
+    let vendor = state
+        .vendors
+        .get(handle)
+        .ok_or_else(|| EscrowError::VendorNotFound(handle.to_string()))?;
+    if vendor.status != VendorStatus::Active {
+        return Err(EscrowError::VendorInactive(handle.to_string()));
+    }
+    if vendor.principal != payee {
+        return Err(EscrowError::InvalidArgument(format!("payee is not vendor {}", handle)));
+    }
+    Ok(vendor
+        .payout_accounts
+        .iter()
+        .find(|p| p.ledger == ledger)
+        .map(|p| p.account))
+}
+
+pub fn set_verified(state: &mut State, handle: &str, verified: bool, now: u64) -> EscrowResult<()> {
+    let vendor = state
+        .vendors
+        .get_mut(handle)
+        .ok_or_else(|| EscrowError::VendorNotFound(handle.to_string()))?;
+    vendor.verified = verified;
+    vendor.updated_at = now;
+    Ok(())
+}
+
+/// Lifting a suspension leaves the vendor inactive until they turn it back on.
+pub fn set_suspended(state: &mut State, handle: &str, suspended: bool, now: u64) -> EscrowResult<()> {
+    let vendor = state
+        .vendors
+        .get_mut(handle)
+        .ok_or_else(|| EscrowError::VendorNotFound(handle.to_string()))?;
+    vendor.status = match (suspended, vendor.status) {
+        (true, _) => VendorStatus::Suspended,
+        (false, VendorStatus::Suspended) => VendorStatus::Inactive,
+        (false, status) => status,
+    };
+    vendor.updated_at = now;
+    Ok(())
+}
@@ -0,0 +1,368 @@
+//! PocketIC harness for the escrow canister. Installs the `PIW_backend` wasm
+//! next to a local ICRC-1/ICRC-2 ledger and a mock KYC issuer canister, and
+//! gives the tests a handful of principals with funded accounts.
+//!
+//! Nothing is downloaded, so the suite runs offline. It needs:
+//! - `POCKET_IC_BIN`: the PocketIC server binary.
+//! - `PIW_BACKEND_WASM`: defaults to the release build,
+//!   `target/wasm32-unknown-unknown/release/PIW_backend.wasm`.
+//! - `PIW_KYC_ISSUER_WASM`: defaults to the release build,
+//!   `target/wasm32-unknown-unknown/release/PIW_kyc_issuer_mock.wasm`.
+//! - `ICRC1_LEDGER_WASM`: the ICRC-1 ledger wasm (plain or gzipped) from the
+//!   IC release matching your PocketIC version.
+//!
+//! The tests are `#[ignore]`d so `cargo test --workspace` stays usable
+//! without these; run them with `cargo test -p PIW_integration_tests --
+//! --ignored`. Once asked for, a missing file fails the test.
+
+#![allow(non_snake_case)]
+
+use candid::{decode_args, encode_args, utils::ArgumentDecoder, utils::ArgumentEncoder};
+use candid::{CandidType, Deserialize, Nat, Principal, Reserved};
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use pocket_ic::{PocketIc, WasmResult};
+use std::path::PathBuf;
+
+pub const LEDGER_FEE: u64 = 10_000;
+pub const INITIAL_BALANCE: u64 = 100_000_000;
+const CANISTER_CYCLES: u128 = 2_000_000_000_000;
+
+pub type CallResult<T> = Result<T, EscrowError>;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContractStatus {
+    Pending,
+    Accepted,
+    Active,
+    Released,
+    Refunded,
+    Disputed,
+}
+
+/// Mirrors the canister's `EscrowError`. Payloads the tests never look at
+/// are decoded as `reserved` rather than mirroring their types.
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+pub enum EscrowError {
+    NotFound,
+    Unauthorized,
+    InvalidStatus(ContractStatus),
+    InvalidArgument(String),
+    UnsupportedLedger(Principal),
+    HashLockMissing,
+    HashLockExpired,
+    HashLockNotExpired,
+    PreimageRevealed,
+    PreimageMismatch,
+    ApprovalRequired,
+    AlreadyApproved,
+    NotApproved,
+    TemplateNotFound(Reserved),
+    EvidenceMissing(Reserved),
+    Paused(Reserved),
+    LimitExceeded(Reserved),
+    VendorNotFound(String),
+    VendorInactive(String),
+    KycRequired { principal: Principal, level: KycLevel },
+    PayoutPending,
+    ContractBusy,
+    CancellationAgreed,
+    Ledger(String),
+    SystemCall(String),
+    Encoding(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KycLevel {
+    Basic,
+    Enhanced,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct KycThreshold {
+    pub ledger: Principal,
+    pub min_amount: u64,
+    pub level: KycLevel,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum DisputeResolution {
+    ReleaseToPayee,
+    RefundToPayer,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum Role {
+    Owner,
+    Admin,
+    Arbiter,
+    Support,
+    Auditor,
+}
+
+/// The subset of `CreateEscrowArgs` the tests need; the optional fields are
+/// left out and decode as `null` on the canister side.
+#[derive(CandidType, Clone, Debug)]
+pub struct CreateEscrowArgs {
+    pub payee: Principal,
+    pub ledger: Principal,
+    pub amount: u64,
+    pub conditions: String,
+}
+
+/// The subset of `EscrowContract` the tests assert on.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EscrowContract {
+    pub id: u64,
+    pub payer: Principal,
+    pub payee: Principal,
+    pub amount: u64,
+    pub status: ContractStatus,
+}
+
+#[derive(CandidType)]
+enum LedgerArgument {
+    Init(LedgerInitArgs),
+}
+
+#[derive(CandidType)]
+struct FeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct ArchiveOptions {
+    num_blocks_to_archive: u64,
+    trigger_threshold: u64,
+    controller_id: Principal,
+}
+
+#[derive(CandidType)]
+struct LedgerInitArgs {
+    minting_account: Account,
+    transfer_fee: Nat,
+    token_symbol: String,
+    token_name: String,
+    metadata: Vec<(String, MetadataValue)>,
+    initial_balances: Vec<(Account, Nat)>,
+    feature_flags: Option<FeatureFlags>,
+    archive_options: ArchiveOptions,
+}
+
+pub struct Env {
+    pub pic: PocketIc,
+    pub backend: Principal,
+    pub ledger: Principal,
+    pub owner: Principal,
+    pub payer: Principal,
+    pub payee: Principal,
+    pub arbiter: Principal,
+    /// The mock issuer canister, which forwards `attest_kyc` and
+    /// `revoke_kyc` to the escrow canister.
+    pub kyc_issuer: Principal,
+}
+
+fn user(n: u8) -> Principal {
+    Principal::from_slice(&[n; 29][..])
+}
+
+/// A workspace wasm build, unless `var` points elsewhere.
+fn built_wasm_path(var: &str, package: &str) -> PathBuf {
+    std::env::var_os(var).map(PathBuf::from).unwrap_or_else(|| {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("../../target/wasm32-unknown-unknown/release/{}.wasm", package))
+    })
+}
+
+fn read_wasm(path: Option<PathBuf>, what: &str) -> Vec<u8> {
+    let path = path.unwrap_or_else(|| panic!("set {} to run the PocketIC tests", what));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read {} at {}: {}", what, path.display(), e))
+}
+
+/// Starts PocketIC with a funded ledger, the escrow canister, with the ledger
+/// already registered, and the mock KYC issuer. Panics if a required file is
+/// missing.
+pub fn setup() -> Env {
+    let server = std::env::var_os("POCKET_IC_BIN").map(PathBuf::from);
+    assert!(
+        server.as_ref().is_some_and(|p| p.is_file()),
+        "set POCKET_IC_BIN to the PocketIC server binary"
+    );
+    let backend_wasm = read_wasm(Some(built_wasm_path("PIW_BACKEND_WASM", "PIW_backend")), "PIW_BACKEND_WASM");
+    let issuer_wasm = read_wasm(Some(built_wasm_path("PIW_KYC_ISSUER_WASM", "PIW_kyc_issuer_mock")), "PIW_KYC_ISSUER_WASM");
+    let ledger_wasm = read_wasm(std::env::var_os("ICRC1_LEDGER_WASM").map(PathBuf::from), "ICRC1_LEDGER_WASM");
+
+    let pic = PocketIc::new();
+    let (owner, payer, payee, arbiter) = (user(1), user(2), user(3), user(4));
+    let minter = user(9);
+
+    let ledger = pic.create_canister_with_settings(Some(owner), None);
+    pic.add_cycles(ledger, CANISTER_CYCLES);
+    let init = LedgerArgument::Init(LedgerInitArgs {
+        minting_account: Account::from(minter),
+        transfer_fee: Nat::from(LEDGER_FEE),
+        token_symbol: "TST".to_string(),
+        token_name: "Test Token".to_string(),
+        metadata: vec![],
+        initial_balances: [payer, payee]
+            .into_iter()
+            .map(|p| (Account::from(p), Nat::from(INITIAL_BALANCE)))
+            .collect(),
+        feature_flags: Some(FeatureFlags { icrc2: true }),
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1_000,
+            trigger_threshold: 2_000,
+            controller_id: owner,
+        },
+    });
+    pic.install_canister(ledger, ledger_wasm, encode_args((init,)).unwrap(), Some(owner));
+
+    let backend = pic.create_canister_with_settings(Some(owner), None);
+    pic.add_cycles(backend, CANISTER_CYCLES);
+    pic.install_canister(backend, backend_wasm, encode_args(()).unwrap(), Some(owner));
+
+    let kyc_issuer = pic.create_canister_with_settings(Some(owner), None);
+    pic.add_cycles(kyc_issuer, CANISTER_CYCLES);
+    pic.install_canister(kyc_issuer, issuer_wasm, encode_args((backend,)).unwrap(), Some(owner));
+
+    let env = Env {
+        pic,
+        backend,
+        ledger,
+        owner,
+        payer,
+        payee,
+        arbiter,
+        kyc_issuer,
+    };
+    let added: (CallResult<()>,) = env.update(owner, "add_ledger", (ledger,));
+    added.0.expect("add_ledger failed");
+    env
+}
+
+/// Mirrors the canister's subaccount layout: the contract ID, big-endian, in
+/// the last eight bytes.
+pub fn escrow_account(backend: Principal, contract_id: u64) -> Account {
+    let mut subaccount: Subaccount = [0; 32];
+    subaccount[24..].copy_from_slice(&contract_id.to_be_bytes());
+    Account {
+        owner: backend,
+        subaccount: Some(subaccount),
+    }
+}
+
+impl Env {
+    pub fn update<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(&self, sender: Principal, method: &str, args: A) -> R {
+        self.call(self.backend, sender, method, args, false)
+    }
+
+    /// Has the mock issuer canister attest `subject`, as an issuer that has
+    /// verified them off-chain would.
+    pub fn attest_via_issuer(&self, subject: Principal, level: KycLevel, expires_at: u64) -> CallResult<()> {
+        let (result,): (CallResult<()>,) =
+            self.call(self.kyc_issuer, self.owner, "attest_kyc", (subject, level, expires_at), false);
+        result
+    }
+
+    pub fn query<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(&self, sender: Principal, method: &str, args: A) -> R {
+        self.call(self.backend, sender, method, args, true)
+    }
+
+    /// Like `update`, but for calls the canister is expected to reject
+    /// outright, e.g. through a guard. Returns the reject message.
+    pub fn update_rejected<A: ArgumentEncoder>(&self, sender: Principal, method: &str, args: A) -> String {
+        match self.pic.update_call(self.backend, sender, method, encode_args(args).unwrap()) {
+            Ok(WasmResult::Reject(msg)) => msg,
+            Ok(WasmResult::Reply(_)) => panic!("{} was expected to be rejected", method),
+            Err(e) => e.description,
+        }
+    }
+
+    fn call<A: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        canister: Principal,
+        sender: Principal,
+        method: &str,
+        args: A,
+        query: bool,
+    ) -> R {
+        let payload = encode_args(args).unwrap();
+        let result = if query {
+            self.pic.query_call(canister, sender, method, payload)
+        } else {
+            self.pic.update_call(canister, sender, method, payload)
+        };
+        match result {
+            Ok(WasmResult::Reply(bytes)) => decode_args(&bytes).unwrap_or_else(|e| panic!("cannot decode {} reply: {}", method, e)),
+            Ok(WasmResult::Reject(msg)) => panic!("{} rejected: {}", method, msg),
+            Err(e) => panic!("{} failed: {}", method, e),
+        }
+    }
+
+    pub fn balance(&self, account: Account) -> u64 {
+        let (balance,): (Nat,) = self.call(self.ledger, Principal::anonymous(), "icrc1_balance_of", (account,), true);
+        balance.0.try_into().expect("balance does not fit in u64")
+    }
+
+    pub fn balance_of(&self, principal: Principal) -> u64 {
+        self.balance(Account::from(principal))
+    }
+
+    /// Lets the escrow canister pull `amount` plus the transfer fee from
+    /// `from`, which is what `fund_escrow` expects.
+    pub fn approve(&self, from: Principal, amount: u64) {
+        let args = ApproveArgs {
+            from_subaccount: None,
+            spender: Account::from(self.backend),
+            amount: Nat::from(amount + LEDGER_FEE),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let (result,): (Result<Nat, ApproveError>,) = self.call(self.ledger, from, "icrc2_approve", (args,), false);
+        result.expect("icrc2_approve failed");
+    }
+
+    pub fn create_escrow(&self, amount: u64) -> u64 {
+        let args = CreateEscrowArgs {
+            payee: self.payee,
+            ledger: self.ledger,
+            amount,
+            conditions: "Deliver the goods".to_string(),
+        };
+        let (result,): (CallResult<u64>,) = self.update(self.payer, "create_escrow", (args,));
+        result.expect("create_escrow failed")
+    }
+
+    /// Creates, accepts and funds an escrow so it is `Active`.
+    pub fn funded_escrow(&self, amount: u64) -> u64 {
+        let id = self.create_escrow(amount);
+        let (accepted,): (CallResult<()>,) = self.update(self.payee, "accept_escrow", (id,));
+        accepted.expect("accept_escrow failed");
+        self.approve(self.payer, amount);
+        let (funded,): (CallResult<u64>,) = self.update(self.payer, "fund_escrow", (id,));
+        funded.expect("fund_escrow failed");
+        id
+    }
+
+    pub fn contract(&self, id: u64) -> EscrowContract {
+        let (contract,): (Option<EscrowContract>,) = self.query(self.payer, "get_contract", (id,));
+        contract.expect("contract not found")
+    }
+
+    pub fn escrow_balance(&self, id: u64) -> u64 {
+        self.balance(escrow_account(self.backend, id))
+    }
+
+    /// The replica's current time in nanoseconds since the epoch.
+    pub fn now(&self) -> u64 {
+        self.pic
+            .get_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("time before the epoch")
+            .as_nanos() as u64
+    }
+}
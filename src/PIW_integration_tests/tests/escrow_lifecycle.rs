@@ -0,0 +1,127 @@
+use PIW_integration_tests::{setup, CallResult, ContractStatus, DisputeResolution, EscrowError, Role, INITIAL_BALANCE, LEDGER_FEE};
+
+const AMOUNT: u64 = 1_000_000;
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn create_accept_fund_release() {
+    let env = setup();
+    let id = env.create_escrow(AMOUNT);
+    assert_eq!(env.contract(id).status, ContractStatus::Pending);
+
+    let (accepted,): (CallResult<()>,) = env.update(env.payee, "accept_escrow", (id,));
+    accepted.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Accepted);
+
+    env.approve(env.payer, AMOUNT);
+    let (funded,): (CallResult<u64>,) = env.update(env.payer, "fund_escrow", (id,));
+    funded.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Active);
+    assert_eq!(env.escrow_balance(id), AMOUNT);
+    // One fee for the approval and one for the transfer into the escrow.
+    assert_eq!(env.balance_of(env.payer), INITIAL_BALANCE - AMOUNT - 2 * LEDGER_FEE);
+
+    let (released,): (CallResult<u64>,) = env.update(env.payer, "release_funds", (id,));
+    released.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Released);
+    assert_eq!(env.escrow_balance(id), 0);
+    assert_eq!(env.balance_of(env.payee), INITIAL_BALANCE + AMOUNT - LEDGER_FEE);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn payee_refunds_an_active_escrow() {
+    let env = setup();
+    let id = env.funded_escrow(AMOUNT);
+
+    let (refunded,): (CallResult<Option<u64>>,) = env.update(env.payee, "refund_funds", (id,));
+    assert!(refunded.unwrap().is_some());
+    assert_eq!(env.contract(id).status, ContractStatus::Refunded);
+    assert_eq!(env.escrow_balance(id), 0);
+    assert_eq!(env.balance_of(env.payer), INITIAL_BALANCE - 3 * LEDGER_FEE);
+    assert_eq!(env.balance_of(env.payee), INITIAL_BALANCE);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn payer_cancels_before_funding_without_moving_funds() {
+    let env = setup();
+    let id = env.create_escrow(AMOUNT);
+
+    let (refunded,): (CallResult<Option<u64>>,) = env.update(env.payer, "refund_funds", (id,));
+    assert_eq!(refunded.unwrap(), None);
+    assert_eq!(env.contract(id).status, ContractStatus::Refunded);
+    assert_eq!(env.balance_of(env.payer), INITIAL_BALANCE);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn only_the_payer_may_fund_or_release() {
+    let env = setup();
+    let id = env.funded_escrow(AMOUNT);
+
+    let (released,): (CallResult<u64>,) = env.update(env.payee, "release_funds", (id,));
+    assert_eq!(released, Err(EscrowError::Unauthorized));
+    let (released,): (CallResult<u64>,) = env.update(env.arbiter, "release_funds", (id,));
+    assert_eq!(released, Err(EscrowError::Unauthorized));
+    assert_eq!(env.contract(id).status, ContractStatus::Active);
+    assert_eq!(env.escrow_balance(id), AMOUNT);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn arbiter_resolves_dispute_with_refund() {
+    let env = setup();
+    let id = env.funded_escrow(AMOUNT);
+
+    let (disputed,): (CallResult<()>,) = env.update(env.payer, "dispute_contract", (id, "Item never arrived".to_string()));
+    disputed.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Disputed);
+
+    // Only principals holding the Arbiter role get past the guard.
+    env.update_rejected(env.arbiter, "resolve_dispute", (id, DisputeResolution::RefundToPayer));
+    let (granted,): (CallResult<()>,) = env.update(env.owner, "grant_role", (env.arbiter, Role::Arbiter));
+    granted.unwrap();
+
+    let (resolved,): (CallResult<u64>,) = env.update(env.arbiter, "resolve_dispute", (id, DisputeResolution::RefundToPayer));
+    resolved.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Refunded);
+    assert_eq!(env.escrow_balance(id), 0);
+    assert_eq!(env.balance_of(env.payer), INITIAL_BALANCE - 3 * LEDGER_FEE);
+    assert_eq!(env.balance_of(env.payee), INITIAL_BALANCE);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn arbiter_resolves_dispute_with_release() {
+    let env = setup();
+    let id = env.funded_escrow(AMOUNT);
+
+    let (disputed,): (CallResult<()>,) = env.update(env.payee, "dispute_contract", (id, "Payer went silent".to_string()));
+    disputed.unwrap();
+    let (released,): (CallResult<u64>,) = env.update(env.payer, "release_funds", (id,));
+    assert_eq!(released, Err(EscrowError::InvalidStatus(ContractStatus::Disputed)));
+
+    let (granted,): (CallResult<()>,) = env.update(env.owner, "grant_role", (env.arbiter, Role::Arbiter));
+    granted.unwrap();
+    let (resolved,): (CallResult<u64>,) = env.update(env.arbiter, "resolve_dispute", (id, DisputeResolution::ReleaseToPayee));
+    resolved.unwrap();
+    assert_eq!(env.contract(id).status, ContractStatus::Released);
+    assert_eq!(env.escrow_balance(id), 0);
+    assert_eq!(env.balance_of(env.payee), INITIAL_BALANCE + AMOUNT - LEDGER_FEE);
+}
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn a_retried_release_returns_the_first_result() {
+    let env = setup();
+    let id = env.funded_escrow(AMOUNT);
+    let key = Some("release-1".to_string());
+
+    let (first,): (CallResult<u64>,) = env.update(env.payer, "release_funds", (id, key.clone()));
+    let (retried,): (CallResult<u64>,) = env.update(env.payer, "release_funds", (id, key));
+    assert_eq!(retried, Ok(first.unwrap()));
+    let (unkeyed,): (CallResult<u64>,) = env.update(env.payer, "release_funds", (id,));
+    assert_eq!(unkeyed, Err(EscrowError::InvalidStatus(ContractStatus::Released)));
+    assert_eq!(env.balance_of(env.payee), INITIAL_BALANCE + AMOUNT - LEDGER_FEE);
+}
@@ -0,0 +1,42 @@
+use PIW_integration_tests::{setup, CallResult, CreateEscrowArgs, EscrowError, KycLevel, KycThreshold};
+
+const SMALL: u64 = 1_000_000;
+const LARGE: u64 = 50_000_000;
+const HOUR_NANOS: u64 = 3_600 * 1_000_000_000;
+
+#[test]
+#[ignore = "needs PocketIC; see src/PIW_integration_tests/src/lib.rs"]
+fn large_escrows_need_attested_parties() {
+    let env = setup();
+    let expires_at = env.now() + HOUR_NANOS;
+    assert_eq!(env.attest_via_issuer(env.payer, KycLevel::Basic, expires_at), Err(EscrowError::Unauthorized));
+    env.update::<_, ()>(env.owner, "set_kyc_issuer", (env.kyc_issuer, true));
+    let threshold = KycThreshold {
+        ledger: env.ledger,
+        min_amount: 10_000_000,
+        level: KycLevel::Basic,
+    };
+    env.update::<_, ()>(env.owner, "set_kyc_thresholds", (vec![threshold],));
+
+    env.create_escrow(SMALL);
+    let args = CreateEscrowArgs {
+        payee: env.payee,
+        ledger: env.ledger,
+        amount: LARGE,
+        conditions: "Deliver the goods".to_string(),
+    };
+    let (blocked,): (CallResult<u64>,) = env.update(env.payer, "create_escrow", (args.clone(),));
+    assert_eq!(blocked, Err(EscrowError::KycRequired { principal: env.payer, level: KycLevel::Basic }));
+
+    // Only trusted issuers can attest.
+    let (forged,): (CallResult<()>,) = env.update(env.payer, "attest_kyc", (env.payer, KycLevel::Enhanced, expires_at));
+    assert_eq!(forged, Err(EscrowError::Unauthorized));
+
+    env.attest_via_issuer(env.payer, KycLevel::Basic, expires_at).unwrap();
+    let (blocked,): (CallResult<u64>,) = env.update(env.payer, "create_escrow", (args.clone(),));
+    assert_eq!(blocked, Err(EscrowError::KycRequired { principal: env.payee, level: KycLevel::Basic }));
+
+    env.attest_via_issuer(env.payee, KycLevel::Basic, expires_at).unwrap();
+    let (created,): (CallResult<u64>,) = env.update(env.payer, "create_escrow", (args,));
+    created.unwrap();
+}
@@ -0,0 +1,42 @@
+//! A KYC issuer canister for the PocketIC tests. A real issuer verifies an
+//! identity off-chain and then calls the escrow canister; this one forwards
+//! `attest_kyc` and `revoke_kyc` unchanged, so the escrow canister sees a
+//! call from another canister and the test sees its reply as is.
+
+#![allow(non_snake_case)]
+
+use candid::{encode_args, CandidType, Deserialize, Principal};
+use ic_cdk::api::call::{call_raw, reject, reply_raw};
+use std::cell::Cell;
+
+thread_local! {
+    static ESCROW: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+}
+
+#[derive(CandidType, Deserialize)]
+enum KycLevel {
+    Basic,
+    Enhanced,
+}
+
+#[ic_cdk::init]
+fn init(escrow: Principal) {
+    ESCROW.with(|e| e.set(escrow));
+}
+
+async fn forward(method: &str, args: Vec<u8>) {
+    match call_raw(ESCROW.with(Cell::get), method, args, 0).await {
+        Ok(bytes) => reply_raw(&bytes),
+        Err((code, msg)) => reject(&format!("{} failed: {:?} {}", method, code, msg)),
+    }
+}
+
+#[ic_cdk::update(manual_reply = true)]
+async fn attest_kyc(subject: Principal, level: KycLevel, expires_at: u64) {
+    forward("attest_kyc", encode_args((subject, level, expires_at)).unwrap()).await;
+}
+
+#[ic_cdk::update(manual_reply = true)]
+async fn revoke_kyc(subject: Principal) {
+    forward("revoke_kyc", encode_args((subject,)).unwrap()).await;
+}